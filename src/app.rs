@@ -1,181 +1,1025 @@
-use std::{env, fs};
-use std::any::Any;
-use std::fs::File;
-use std::io::BufReader;
+use std::fs;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
 use eframe::egui;
 use elevenlabs_rs::{Bytes};
-use elevenlabs_rs::utils::{play, save};
-use rodio::{Sink, cpal, Decoder, Device, DeviceTrait, OutputStream, Source};
+use elevenlabs_rs::utils::save;
+use rodio::{cpal, Device};
 use rodio::cpal::traits::HostTrait;
 use serde::{Deserialize, Serialize};
-use crate::{Elabs, ErrorManager, Voice};
-use crate::device::PSDevice;
+use serde_json::json;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use crate::{Elabs, ErrorLog, ErrorManager, ErrorSender, ErrorSource, Model, PleaseSpeakError, Voice, VoiceSettings};
+use crate::audio::ExportFormat;
+use crate::batch::{Batch, BatchAction, DEFAULT_BATCH_TEMPLATE};
+use crate::cache::{SpeechCache, DEFAULT_CACHE_SIZE_MB};
+use crate::device::{resolve_routes, OutputRoute, PSDevice};
+use crate::history::{History, HistoryAction, DEFAULT_HISTORY_SIZE_MB};
+use crate::naming::{output_path, render_template, Collision, DEFAULT_FILE_NAME_TEMPLATE};
+use crate::jobs::{JobQueue, JobSpec, JobStatus, DEFAULT_MAX_CONCURRENT_JOBS};
+use crate::playback::{PlaybackStatus, Player};
+use crate::provider::{runtime, Account, ConnectionState, ProviderKind, SpeechProvider, SpeechRequest};
+use crate::retry::{RetryPolicy, DEFAULT_MAX_ATTEMPTS};
+use crate::secrets::KeyStorage;
+use crate::project::{Project, ProjectAction, ProjectLine};
+use crate::soundboard::{Soundboard, SoundboardAction, SoundboardPad};
+use crate::server::{generate_token, ApiCall, ApiReply, ApiServer, SpeakBody, DEFAULT_API_SERVER_PORT};
 
 pub const APP_KEY: &str = "please_speak";
+pub const APP_NAME: &str = "Please Speak";
+/// How often output devices are listed again while watched, to follow devices being plugged in and out.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Outcome of a connection attempt made by [`TtsApp::connect`].
+struct ConnectionAttempt {
+    attempt: u64,
+    result: Result<Arc<dyn SpeechProvider>, PleaseSpeakError>,
+}
+
+/// What the key storage was last asked to hold, so it is only written again when one of these changes.
+#[derive(Clone, PartialEq)]
+struct StoredKey {
+    api_key: String,
+    storage: KeyStorage,
+    passphrase: String,
+}
+
+/// Outcome of reading or writing the key storage, done off the UI thread.
+enum KeyTask {
+    Loaded(StoredKey, Result<Option<String>, PleaseSpeakError>),
+    Stored(StoredKey, Result<(), PleaseSpeakError>),
+}
 
 pub struct TtsApp {
     configuration: Configuration,
 
-    elabs: Elabs,
+    /// Where every request runs, see [`runtime`].
+    runtime: Handle,
+    connection_task: Option<JoinHandle<()>>,
+    jobs: JobQueue,
+    jobs_open: bool,
+
+    /// The local API, running while enabled in the configuration.
+    api_server: Option<ApiServer>,
+    api_calls_rx: Receiver<ApiCall>,
+    api_calls_tx: Sender<ApiCall>,
+    /// Jobs queued through the local API to play once done.
+    api_playback: HashSet<u64>,
+    /// Jobs queued through the local API, their audio is kept until a client fetched it.
+    api_jobs: HashSet<u64>,
+    /// The job revision and voice list last shared with the local API.
+    api_published: Option<(u64, u64)>,
+
+    provider: Arc<dyn SpeechProvider>,
+    connection: ConnectionState,
+    /// Increased on each connection, so the result of an older attempt is ignored.
+    connection_attempt: u64,
+    connection_rx: Receiver<ConnectionAttempt>,
+    connection_tx: Sender<ConnectionAttempt>,
+    /// The provider and key of the last connection, reconnecting is only needed when they change.
+    connected_with: Option<(ProviderKind, String)>,
     voices: Vec<Voice>,
+    /// Increased each time the voices are listed again.
+    voices_revision: u64,
+    models: Vec<Model>,
     last_generated: Option<Bytes>,
+    /// What produced the last generation, its file is named from it when saved.
+    last_generated_request: Option<SpeechRequest>,
+    /// Name of the last generation when it has no single request, like a project mix.
     last_generated_file_name: String,
     last_generated_file_path: String,
+    last_generated_cached: bool,
+    force_regenerate: bool,
 
     settings_modal: bool,
 
-    api_error_manager: ErrorManager,
-    elabs_error_manager: ErrorManager,
+    /// Unlocks the encrypted key file, only ever kept in memory.
+    passphrase: String,
+    unlock_modal: bool,
+    reveal_api_key: bool,
+    stored_key: Option<StoredKey>,
+    key_rx: Receiver<KeyTask>,
+    key_tx: Sender<KeyTask>,
+    /// Key storage reads and writes still running.
+    key_tasks: usize,
+
+    /// Who the key belongs to, or why it could not be checked.
+    account: Option<Result<Account, PleaseSpeakError>>,
+    account_loading_rx: Receiver<Result<Account, PleaseSpeakError>>,
+    account_loading_tx: Sender<Result<Account, PleaseSpeakError>>,
+    account_loading: bool,
+
+    history: History,
+    history_open: bool,
+
+    batch: Batch,
+    batch_open: bool,
+
+    soundboard: Soundboard,
+    soundboard_open: bool,
+    /// Pads being rendered, by job.
+    soundboard_jobs: HashMap<u64, u64>,
 
-    voices_loading_rx: Receiver<Vec<Voice>>,
-    voices_loading_tx: Sender<Vec<Voice>>,
+    project: Project,
+    project_open: bool,
+    /// Project lines being rendered, by job.
+    project_jobs: HashMap<u64, u64>,
+
+    error_tx: ErrorSender,
+    error_manager: ErrorManager,
+    error_log: ErrorLog,
+    error_log_open: bool,
+
+    player: Player,
+
+    voices_loading_rx: Receiver<Result<Vec<Voice>, PleaseSpeakError>>,
+    voices_loading_tx: Sender<Result<Vec<Voice>, PleaseSpeakError>>,
     voices_loading: bool,
 
-    generate_loading_rx: Receiver<Bytes>,
-    generate_loading_tx: Sender<Bytes>,
-    generate_loading: bool,
+    models_loading_rx: Receiver<Vec<Model>>,
+    models_loading_tx: Sender<Vec<Model>>,
+
+    voice_settings_loading_rx: Receiver<(String, VoiceSettings)>,
+    voice_settings_loading_tx: Sender<(String, VoiceSettings)>,
+    voice_settings_loading: bool,
+
 
     devices: Vec<PSDevice>,
+    devices_loading_rx: Receiver<Result<Vec<PSDevice>, PleaseSpeakError>>,
+    devices_loading_tx: Sender<Result<Vec<PSDevice>, PleaseSpeakError>>,
+    devices_loading: bool,
+    devices_listed_at: Option<Instant>,
+    /// Whether the devices were listed once, so startup is not taken for a device plugged in.
+    devices_listed: bool,
+    /// Whether the last listing failed, so a failure repeating on each poll is reported once.
+    devices_failed: bool,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Configuration {
-    api_key: String,
-    text: String,
-    voice: Voice,
-    save_to: String,
-    output_device: PSDevice,
+    pub(crate) provider: ProviderKind,
+    /// Lives in the key storage, only read from here to migrate older configurations.
+    #[serde(skip_serializing)]
+    pub(crate) api_key: String,
+    /// A key read from an older configuration, persisted until it is safely in the key storage.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub(crate) unstored_api_key: String,
+    pub(crate) key_storage: KeyStorage,
+    pub(crate) text: String,
+    pub(crate) voice: Voice,
+    pub(crate) model: Model,
+    /// Tuned settings by voice id, voices missing here use their own settings.
+    pub(crate) voice_settings: HashMap<String, VoiceSettings>,
+    pub(crate) save_to: String,
+    /// Names saved files, see [`render_template`]. May hold `/` to save into subfolders.
+    pub(crate) file_name_template: String,
+    pub(crate) collision: Collision,
+    /// Fills `{counter}`, increased on each save. Only the app keeps it, the CLI refuses templates using it.
+    pub(crate) save_counter: u64,
+    /// Devices the speech is played on together. A device unplugged is replaced by the default
+    /// output until it is back.
+    pub(crate) output_routes: Vec<OutputRoute>,
+    /// Single device of older versions, only read to migrate it to a route.
+    #[serde(skip_serializing)]
+    pub(crate) output_device: PSDevice,
+    pub(crate) export_format: ExportFormat,
+    pub(crate) cache_enabled: bool,
+    pub(crate) cache_size_mb: u64,
+    /// The oldest generations are dropped from the history past this.
+    pub(crate) history_size_mb: u64,
+    /// Starts playback on the output device while the audio is still being generated.
+    pub(crate) stream_playback: bool,
+    /// Generation jobs running at once, the others wait in the queue.
+    pub(crate) max_concurrent_jobs: usize,
+    /// Attempts for each API request before giving up on transient failures.
+    pub(crate) max_attempts: u32,
+    /// Serves the local API, see [`ApiServer`].
+    pub(crate) api_server_enabled: bool,
+    pub(crate) api_server_port: u16,
+    /// Programs using the local API send it, generated when the API is first enabled.
+    pub(crate) api_server_token: String,
+    /// Names the files of batch rows, see [`Batch`].
+    pub(crate) batch_template: String,
+    /// The project last opened, see [`Project`].
+    pub(crate) project_path: String,
 }
 
 impl Default for Configuration {
     fn default() -> Self {
         Self {
+            provider: ProviderKind::default(),
             api_key: "".to_owned(),
+            unstored_api_key: "".to_owned(),
+            key_storage: KeyStorage::default(),
             text: "Hello World!".to_owned(),
             voice: Voice::default(),
+            model: Model::default(),
+            voice_settings: HashMap::new(),
             save_to: "".to_owned(),
-            output_device: PSDevice::new(cpal::default_host().output_devices().unwrap().next().unwrap())
+            file_name_template: DEFAULT_FILE_NAME_TEMPLATE.to_owned(),
+            collision: Collision::default(),
+            save_counter: 0,
+            output_routes: vec![OutputRoute::default()],
+            output_device: PSDevice::default(),
+            export_format: ExportFormat::default(),
+            cache_enabled: true,
+            cache_size_mb: DEFAULT_CACHE_SIZE_MB,
+            history_size_mb: DEFAULT_HISTORY_SIZE_MB,
+            stream_playback: false,
+            max_concurrent_jobs: DEFAULT_MAX_CONCURRENT_JOBS,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            api_server_enabled: false,
+            api_server_port: DEFAULT_API_SERVER_PORT,
+            api_server_token: "".to_owned(),
+            batch_template: DEFAULT_BATCH_TEMPLATE.to_owned(),
+            project_path: "".to_owned(),
         }
     }
 }
 
+impl Configuration {
+    /// Reads the configuration the app persisted, without needing an eframe context.
+    pub fn load() -> Option<Self> {
+        let path = eframe::storage_dir(APP_NAME)?.join("app.ron");
+        let content = fs::read_to_string(path).ok()?;
+        let kv: HashMap<String, String> = ron::from_str(&content).ok()?;
+        let mut configuration: Self = ron::from_str(kv.get(APP_KEY)?).ok()?;
+        configuration.migrate();
+        Some(configuration)
+    }
+
+    /// Moves settings saved by older versions to where they are kept now.
+    pub(crate) fn migrate(&mut self) {
+        if self.api_key.is_empty() {
+            self.api_key = self.unstored_api_key.clone();
+        }
+        if !self.output_device.is_default() {
+            self.output_routes = vec![OutputRoute::new(std::mem::take(&mut self.output_device))];
+        }
+    }
+
+    pub(crate) fn get_voice_settings(&self, voice: &Voice) -> Option<VoiceSettings> {
+        self.voice_settings.get(voice.get_voice_id()).cloned()
+    }
+}
+
 impl TtsApp {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        let (api_error_tx, api_error_rx) = async_channel::unbounded();
-        let (elabs_error_tx, elabs_error_rx) = async_channel::unbounded();
+        let (error_tx, error_rx) = async_channel::unbounded();
 
+        let (connection_tx, connection_rx) = channel();
         let (voices_loading_tx, voices_loading_rx) = channel();
-        let (generate_loading_tx, generate_loading_rx) = channel();
+        let (models_loading_tx, models_loading_rx) = channel();
+        let (account_loading_tx, account_loading_rx) = channel();
+        let (voice_settings_loading_tx, voice_settings_loading_rx) = channel();
+        let (devices_loading_tx, devices_loading_rx) = channel();
+        let (api_calls_tx, api_calls_rx) = channel();
+        let (key_tx, key_rx) = channel();
 
         let mut configuration: Configuration = Configuration::default();
         if let Some(storage) = cc.storage {
             configuration = eframe::get_value(storage, APP_KEY).unwrap_or_default();
+            configuration.migrate();
         }
 
-        let elabs = Elabs::new(api_error_tx, elabs_error_tx);
-        Self {
+        let history = History::load(configuration.history_size_mb);
+        let project = Project::load(runtime().handle().clone(), &configuration.project_path);
+        let provider = Arc::new(Elabs::new(RetryPolicy::new(configuration.max_attempts)));
+        let mut app = Self {
             configuration,
-            elabs,
+            runtime: runtime().handle().clone(),
+            connection_task: None,
+            jobs: JobQueue::new(runtime().handle().clone()),
+            jobs_open: false,
+            api_server: None,
+            api_calls_rx,
+            api_calls_tx,
+            api_playback: HashSet::new(),
+            api_jobs: HashSet::new(),
+            api_published: None,
+            provider,
+            connection: ConnectionState::default(),
+            connection_attempt: 0,
+            connection_rx,
+            connection_tx,
+            connected_with: None,
             voices: Vec::new(),
+            voices_revision: 0,
+            models: Vec::new(),
             last_generated: None,
+            last_generated_request: None,
             last_generated_file_name: "".to_string(),
             last_generated_file_path: "".to_string(),
+            last_generated_cached: false,
+            force_regenerate: false,
             settings_modal: false,
-            api_error_manager: ErrorManager::new("Api error".to_string(), api_error_rx),
-            elabs_error_manager: ErrorManager::new("Elabs error".to_string(), elabs_error_rx),
+            passphrase: String::new(),
+            unlock_modal: false,
+            reveal_api_key: false,
+            stored_key: None,
+            key_rx,
+            key_tx,
+            key_tasks: 0,
+
+            account: None,
+            account_loading_rx,
+            account_loading_tx,
+            account_loading: false,
+            history,
+            history_open: false,
+            batch: Batch::new(runtime().handle().clone()),
+            batch_open: false,
+            soundboard: Soundboard::load(),
+            soundboard_open: false,
+            soundboard_jobs: HashMap::new(),
+            project,
+            project_open: false,
+            project_jobs: HashMap::new(),
+            error_tx: error_tx.clone(),
+            error_manager: ErrorManager::new(error_rx),
+            error_log: ErrorLog::default(),
+            error_log_open: false,
+
+            player: Player::new(error_tx),
 
             voices_loading_rx,
             voices_loading_tx,
             voices_loading: false,
 
-            generate_loading_rx,
-            generate_loading_tx,
-            generate_loading: false,
+            models_loading_rx,
+            models_loading_tx,
+
+            voice_settings_loading_rx,
+            voice_settings_loading_tx,
+            voice_settings_loading: false,
 
             devices: Vec::new(),
-        }
+            devices_loading_rx,
+            devices_loading_tx,
+            devices_loading: false,
+            devices_listed_at: None,
+            devices_listed: false,
+            devices_failed: false,
+        };
+        app.sync_api_server(&cc.egui_ctx);
+        app
     }
 
-    pub(crate) fn get_devices() -> Vec<Device> {
+    /// Shows `error` to the user and keeps it in the error log.
+    fn report(&self, source: ErrorSource, error: PleaseSpeakError) {
+        let _ = self.error_tx.send_blocking((source, error));
+    }
+
+    pub(crate) fn get_devices() -> Result<Vec<Device>, PleaseSpeakError> {
         let host = cpal::default_host();
-        let devices = host.output_devices().unwrap();
-        devices.map(|d| d).collect()
+        let devices = host.output_devices().map_err(|e| PleaseSpeakError::AudioDevice(e.to_string()))?;
+        Ok(devices.collect())
     }
 
-    pub fn init(&mut self) {
-        self.elabs.init(self.configuration.api_key.clone());
+    /// Recreates the configured provider with the current API key in the background, the new
+    /// provider replaces the current one once connected.
+    pub fn connect(&mut self) {
+        if let Some(task) = self.connection_task.take() {
+            task.abort();
+        }
+        self.connected_with = Some((self.configuration.provider, self.configuration.api_key.clone()));
+        if self.configuration.api_key.is_empty() {
+            self.connection = ConnectionState::Disconnected;
+            return;
+        }
 
-        if !self.elabs.connected() {
+        self.connection_attempt += 1;
+        self.connection = ConnectionState::Connecting;
+
+        let attempt = self.connection_attempt;
+        let kind = self.configuration.provider;
+        let api_key = self.configuration.api_key.clone();
+        let retry = RetryPolicy::new(self.configuration.max_attempts);
+        let tx = self.connection_tx.clone();
+        self.connection_task = Some(self.runtime.spawn(async move {
+            let result = kind.create(api_key, retry).await;
+            tx.send(ConnectionAttempt { attempt, result }).unwrap()
+        }));
+    }
+
+    /// Connects again when the provider or the API key changed since the last connection.
+    fn connect_if_changed(&mut self) {
+        if self.connected_with.as_ref() != Some(&(self.configuration.provider, self.configuration.api_key.clone())) {
+            self.connect();
+        }
+    }
+
+    /// Reads the API key from the key storage in the background, or moves one persisted by an
+    /// older version there. The app connects once the key is read.
+    pub fn load_api_key(&mut self) {
+        if !self.configuration.api_key.is_empty() {
+            self.configuration.unstored_api_key = self.configuration.api_key.clone();
+            self.store_api_key();
             return;
         }
 
-        self.load_api_resources();
+        let storage = self.configuration.key_storage;
+        if storage == KeyStorage::EncryptedFile && self.passphrase.is_empty() {
+            self.unlock_modal = storage.exists();
+            return;
+        }
+
+        self.key_tasks += 1;
+        self.connection = ConnectionState::Connecting;
+        let key = StoredKey {
+            api_key: String::new(),
+            storage,
+            passphrase: self.passphrase.clone(),
+        };
+        let tx = self.key_tx.clone();
+        // The keyring can block and the encrypted file is slow to derive on purpose.
+        self.runtime.spawn_blocking(move || {
+            let result = key.storage.load(&key.passphrase);
+            tx.send(KeyTask::Loaded(key, result)).unwrap()
+        });
+    }
+
+    /// Writes the API key to the selected key storage and removes it from the others, in the
+    /// background and only when the key, the storage or the passphrase changed.
+    ///
+    /// When the system keyring is unavailable, the settings are opened to pick a passphrase
+    /// for the encrypted file instead. A key being migrated stays in the configuration until then.
+    pub fn store_api_key(&mut self) {
+        if self.configuration.api_key.is_empty() {
+            return;
+        }
+
+        let key = StoredKey {
+            api_key: self.configuration.api_key.clone(),
+            storage: self.configuration.key_storage,
+            passphrase: self.passphrase.clone(),
+        };
+        if self.stored_key.as_ref() == Some(&key) {
+            return;
+        }
+
+        self.stored_key = Some(key.clone());
+        self.key_tasks += 1;
+        let tx = self.key_tx.clone();
+        self.runtime.spawn_blocking(move || {
+            let result = key.storage.save(&key.api_key, &key.passphrase);
+            if result.is_ok() {
+                for other in KeyStorage::ALL.into_iter().filter(|other| *other != key.storage) {
+                    let _ = other.delete();
+                }
+            }
+            tx.send(KeyTask::Stored(key, result)).unwrap()
+        });
+    }
+
+    fn key_task_done(&mut self, task: KeyTask) {
+        self.key_tasks -= 1;
+        match task {
+            KeyTask::Loaded(key, result) => {
+                match result {
+                    Ok(Some(api_key)) => {
+                        self.configuration.api_key = api_key.clone();
+                        self.stored_key = Some(StoredKey { api_key, ..key });
+                        self.unlock_modal = false;
+                    }
+                    Ok(None) => self.unlock_modal = false,
+                    Err(error) => {
+                        self.report(ErrorSource::Secrets, error);
+                    }
+                }
+                self.connect();
+            }
+            KeyTask::Stored(key, Err(error)) => {
+                self.stored_key = None;
+                if !self.configuration.unstored_api_key.is_empty() {
+                    self.configuration.unstored_api_key = key.api_key;
+                }
+                let error = match key.storage {
+                    KeyStorage::Keyring => {
+                        self.configuration.key_storage = KeyStorage::EncryptedFile;
+                        self.settings_modal = true;
+                        PleaseSpeakError::Other(format!("{}\nChoose a passphrase in the settings to keep the API key in an encrypted file instead.", error.get_message()))
+                    }
+                    KeyStorage::EncryptedFile => error,
+                };
+                self.report(ErrorSource::Secrets, error);
+            }
+            KeyTask::Stored(_, Ok(())) => self.configuration.unstored_api_key.clear(),
+        }
+    }
+
+    pub fn init(&mut self) {
+        self.load_api_key();
         self.security_checks();
 
-        let devices = Self::get_devices();
-        for device in devices {
-            self.devices.push(PSDevice::new(device));
+        self.refresh_devices();
+
+        // A key being read from the storage connects once read.
+        if self.connection != ConnectionState::Connecting {
+            self.connect();
+        }
+    }
+
+    /// Lists the output devices again in the background, enumerating can take a while on some hosts.
+    pub fn refresh_devices(&mut self) {
+        if self.devices_loading {
+            return
+        }
+
+        self.devices_loading = true;
+        self.devices_listed_at = Some(Instant::now());
+
+        let tx = self.devices_loading_tx.clone();
+        self.runtime.spawn_blocking(move || {
+            let _ = tx.send(PSDevice::list());
+        });
+    }
+
+    /// Where to play, see [`resolve_routes`].
+    pub fn output_routes(&self) -> Vec<OutputRoute> {
+        resolve_routes(&self.configuration.output_routes, &self.devices)
+    }
+
+    /// Whether devices are listed again every [`DEVICE_POLL_INTERVAL`]: only while a route
+    /// plays on a chosen device, or the settings are open to pick one.
+    fn devices_watched(&self) -> bool {
+        self.settings_modal || self.configuration.output_routes.iter().any(|route| route.enabled && !route.device.is_default())
+    }
+
+    /// Keeps the new device list, telling when a routed device goes away. The settings show
+    /// when it is back.
+    fn update_devices(&mut self, devices: Vec<PSDevice>) {
+        let routed = self.configuration.output_routes.iter().filter(|route| route.enabled && !route.device.is_default());
+        for route in routed.filter(|_| self.devices_listed) {
+            if self.devices.contains(&route.device) && !devices.contains(&route.device) {
+                let message = format!("\"{}\" was unplugged, playing on the default output until it is back", route.device.get_device_name());
+                self.report(ErrorSource::Playback, PleaseSpeakError::AudioDevice(message));
+            }
+        }
+
+        self.devices = devices;
+        self.devices_listed = true;
+    }
+
+    /// Lists the output routes with their device, volume and toggle.
+    fn output_routes_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Output devices:");
+            if ui.add_enabled(!self.devices_loading, egui::Button::new("Refresh devices")).clicked() {
+                self.refresh_devices();
+            }
+        });
+
+        let mut removed = None;
+        for (index, route) in self.configuration.output_routes.iter_mut().enumerate() {
+            let selected = if route.device.is_default() || self.devices.contains(&route.device) {
+                route.device.get_device_name()
+            } else {
+                format!("{} (unplugged, using the default output)", route.device.get_device_name())
+            };
+
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut route.enabled, "");
+                egui::ComboBox::from_id_source(("output_route", index))
+                    .selected_text(selected)
+                    .show_ui(ui, |ui| {
+                        let default = PSDevice::default();
+                        ui.selectable_value(&mut route.device, default.clone(), default.get_device_name());
+                        for device in &self.devices {
+                            ui.selectable_value(&mut route.device, device.clone(), device.get_device_name());
+                        }
+                    });
+                ui.add(egui::Slider::new(&mut route.volume, 0.0..=1.5).text("Volume"));
+                if ui.button("Remove").clicked() {
+                    removed = Some(index);
+                }
+            });
+        }
+        if let Some(index) = removed {
+            self.configuration.output_routes.remove(index);
+        }
+
+        if ui.button("Add output").clicked() {
+            self.configuration.output_routes.push(OutputRoute::default());
         }
     }
 
     pub fn load_api_resources(&mut self) {
-        if !self.elabs.connected() {
+        if !self.provider.connected() {
             return
         }
 
         self.voices_loading = true;
+        self.account_loading = true;
 
-        let app = Arc::new(self);
-        let elabs = app.elabs.clone();
-        let tx = app.voices_loading_tx.clone();
-        std::thread::spawn(move || {
-            let voices = elabs.run_sync(|elabs| {
-                elabs.get_voices(false)
+        let provider = self.provider.clone();
+        let tx = self.voices_loading_tx.clone();
+        let models_tx = self.models_loading_tx.clone();
+        let account_tx = self.account_loading_tx.clone();
+        let voices_provider = provider.clone();
+        self.runtime.spawn(async move {
+            tx.send(voices_provider.get_voices().await).unwrap()
+        });
+
+        let account_provider = provider.clone();
+        let error_tx = self.error_tx.clone();
+        self.runtime.spawn(async move {
+            let account = account_provider.get_account().await;
+            if let Err(error) = &account {
+                let _ = error_tx.send((ErrorSource::Api, error.clone())).await;
+            }
+            account_tx.send(account).unwrap()
+        });
+
+        if provider.capabilities().model_selection {
+            let error_tx = self.error_tx.clone();
+            self.runtime.spawn(async move {
+                match provider.get_models().await {
+                    Ok(models) => models_tx.send(models).unwrap(),
+                    Err(error) => {
+                        let _ = error_tx.send((ErrorSource::Api, error)).await;
+                    }
+                }
             });
+        }
+    }
 
-            if let Some(voices) = voices {
-                tx.send(voices).unwrap()
+    /// Checks the API key typed in the settings with a throwaway provider, leaving the
+    /// current connection untouched.
+    pub fn test_connection(&mut self) {
+        self.account_loading = true;
+        self.account = None;
+
+        let kind = self.configuration.provider;
+        let api_key = self.configuration.api_key.clone();
+        let retry = RetryPolicy::new(self.configuration.max_attempts);
+        let tx = self.account_loading_tx.clone();
+        self.runtime.spawn(async move {
+            let result = match kind.create(api_key, retry).await {
+                Ok(provider) => provider.get_account().await,
+                Err(reason) => Err(reason),
+            };
+            tx.send(result).unwrap()
+        });
+    }
+
+    /// Fetches the settings saved with the selected voice and uses them as its tuned settings.
+    pub fn load_voice_settings(&mut self) {
+        if !self.provider.connected() {
+            return
+        }
+
+        self.voice_settings_loading = true;
+
+        let provider = self.provider.clone();
+        let tx = self.voice_settings_loading_tx.clone();
+        let voice = self.configuration.voice.clone();
+        let error_tx = self.error_tx.clone();
+        self.runtime.spawn(async move {
+            match provider.get_voice_settings(&voice).await {
+                Ok(settings) => tx.send((voice.get_voice_id().to_string(), settings)).unwrap(),
+                Err(error) => {
+                    let _ = error_tx.send((ErrorSource::Api, error)).await;
+                }
             }
         });
     }
 
     pub fn security_checks(&mut self) {
         if self.configuration.save_to.is_empty() {
-            self.configuration.save_to = std::env::temp_dir().to_str().unwrap().to_string();
+            self.configuration.save_to = std::env::temp_dir().to_string_lossy().to_string();
         }
 
         let path = Path::new(&self.configuration.save_to);
         if !path.exists() {
-            fs::create_dir_all(path).unwrap();
+            if let Err(error) = fs::create_dir_all(path) {
+                let message = format!("Could not create {}: {}", path.display(), error);
+                self.report(ErrorSource::Export, PleaseSpeakError::Filesystem(message));
+            }
+        }
+    }
+
+    /// What the main window would generate.
+    pub fn current_request(&self) -> SpeechRequest {
+        SpeechRequest {
+            text: self.configuration.text.clone(),
+            voice: self.configuration.voice.clone(),
+            model: self.configuration.model.get_model_id().to_string(),
+            settings: self.configuration.get_voice_settings(&self.configuration.voice),
+            previous_text: None,
+            next_text: None,
         }
     }
 
     pub fn generate(&mut self) {
-        if !self.elabs.connected() {
+        self.generate_request(self.current_request(), self.force_regenerate);
+    }
+
+    /// Queues a job generating `request`, going through the cache unless `force` is set.
+    pub fn generate_request(&mut self, request: SpeechRequest, force: bool) {
+        if !self.provider.connected() {
             return
         }
 
-        self.generate_loading = true;
+        let spec = self.job_spec(request, force);
+        self.jobs.submit(spec);
+    }
 
-        let app = Arc::new(self);
+    /// How the configuration says `request` is generated.
+    fn job_spec(&self, request: SpeechRequest, force: bool) -> JobSpec {
+        let max_characters = self.models.iter()
+            .find(|model| model.get_model_id() == request.model)
+            .unwrap_or(&self.configuration.model)
+            .get_max_characters();
 
-        let elabs = app.elabs.clone();
-        let tx = app.generate_loading_tx.clone();
+        JobSpec {
+            request,
+            force,
+            cache: self.configuration.cache_enabled.then(|| SpeechCache::new(self.configuration.cache_size_mb)),
+            max_characters,
+            stream_to: (self.configuration.stream_playback && !self.devices.is_empty()).then(|| self.output_routes()),
+        }
+    }
+
+    /// Queues a job rendering a soundboard pad. Pads always go through the cache, so they are
+    /// only generated once.
+    fn render_pad(&mut self, pad: SoundboardPad, force: bool) {
+        let spec = JobSpec {
+            cache: Some(SpeechCache::new(self.configuration.cache_size_mb)),
+            stream_to: None,
+            ..self.job_spec(pad.request(), force)
+        };
+        let id = self.jobs.submit(spec);
+        self.soundboard.rendering(pad.id);
+        self.soundboard_jobs.insert(id, pad.id);
+    }
 
-        let text = app.configuration.text.clone();
-        let voice = app.configuration.voice.clone();
+    /// Queues a job rendering a project line, through the cache like soundboard pads.
+    fn render_line(&mut self, line: ProjectLine, force: bool) {
+        if !self.provider.connected() {
+            return
+        }
+
+        let spec = JobSpec {
+            cache: Some(SpeechCache::new(self.configuration.cache_size_mb)),
+            stream_to: None,
+            ..self.job_spec(line.request(), force)
+        };
+        let id = self.jobs.submit(spec);
+        self.project.rendering(line.id);
+        self.project_jobs.insert(id, line.id);
+    }
+
+    /// Hands a job queued again from the jobs window back to whatever submitted it.
+    fn job_retried(&mut self, id: u64) {
+        if let Some(&pad) = self.soundboard_jobs.get(&id) {
+            self.soundboard.rendering(pad);
+        } else if let Some(&line) = self.project_jobs.get(&id) {
+            self.project.rendering(line);
+        } else if self.batch.retried(id) == Some(false) {
+            self.jobs.cancel(id);
+        }
+    }
+
+    fn load_batch(&mut self, path: &str) {
+        if !self.provider.connected() {
+            self.report(ErrorSource::Batch, PleaseSpeakError::Other("Connect to the provider before loading a script, its voices are needed".to_string()));
+            return;
+        }
+
+        self.security_checks();
+        let result = self.batch.load(
+            path,
+            &self.voices,
+            &self.configuration.voice,
+            &self.configuration.save_to,
+            &self.configuration.batch_template,
+        );
+        if let Err(error) = result {
+            self.report(ErrorSource::Batch, error);
+        }
+    }
+
+    /// Queues the next rows of a running batch, keeping as many in the queue as jobs run at once.
+    fn submit_batch_rows(&mut self) {
+        while self.batch.in_flight() < self.configuration.max_concurrent_jobs.max(1) {
+            let Some((index, voice, text)) = self.batch.next_pending() else {
+                break;
+            };
+            let request = SpeechRequest {
+                text,
+                model: self.configuration.model.get_model_id().to_string(),
+                settings: self.configuration.get_voice_settings(&voice),
+                voice,
+                previous_text: None,
+                next_text: None,
+            };
+            let spec = JobSpec {
+                stream_to: None,
+                ..self.job_spec(request, false)
+            };
+            let id = self.jobs.submit(spec);
+            self.batch.submitted(id, index);
+        }
+    }
 
-        std::thread::spawn(move || {
-            let voices = elabs.run_sync(|elabs| {
-                elabs.generate_speak(text, voice, true)
+    /// Starts, restarts or stops the local API to match the configuration.
+    pub fn sync_api_server(&mut self, ctx: &egui::Context) {
+        if !self.configuration.api_server_enabled {
+            self.api_server = None;
+            return;
+        }
+
+        if self.configuration.api_server_token.is_empty() {
+            self.configuration.api_server_token = generate_token();
+        }
+        let port = self.configuration.api_server_port;
+        let token = self.configuration.api_server_token.clone();
+        if self.api_server.as_ref().is_some_and(|server| server.get_port() == port && server.get_token() == token) {
+            return;
+        }
+
+        // Stopped first, it may hold the port the new one listens on.
+        self.api_server = None;
+        match ApiServer::start(port, token, self.api_calls_tx.clone(), ctx.clone()) {
+            Ok(server) => {
+                self.api_server = Some(server);
+                self.api_published = None;
+                self.publish_api_state();
+            }
+            Err(error) => {
+                self.report(ErrorSource::Server, error);
+            }
+        }
+    }
+
+    /// Shares the voices and jobs with the local API when they changed, it answers reads
+    /// without waiting for a frame.
+    fn publish_api_state(&mut self) {
+        let revision = (self.jobs.revision(), self.voices_revision);
+        if let Some(server) = self.api_server.as_ref().filter(|_| self.api_published != Some(revision)) {
+            server.publish(&self.voices, self.jobs.jobs());
+            self.api_published = Some(revision);
+        }
+    }
+
+    /// Queues what a program asked for through the local API, like [`Self::generate`] does.
+    fn api_speak(&mut self, body: SpeakBody) -> ApiReply {
+        if !self.provider.connected() {
+            return ApiReply::error(503, "Not connected to the provider");
+        }
+        if body.text.trim().is_empty() {
+            return ApiReply::error(400, "The text is empty");
+        }
+
+        let voice = match &body.voice {
+            Some(wanted) => {
+                let voice = self.voices.iter()
+                    .find(|voice| voice.get_voice_id() == wanted || voice.get_voice_name().eq_ignore_ascii_case(wanted));
+                match voice {
+                    Some(voice) => voice.clone(),
+                    None => return ApiReply::error(404, &format!("No voice with the id or name \"{}\"", wanted)),
+                }
+            }
+            None => self.configuration.voice.clone(),
+        };
+        let request = SpeechRequest {
+            text: body.text,
+            model: body.model.unwrap_or_else(|| self.configuration.model.get_model_id().to_string()),
+            settings: body.settings.or_else(|| self.configuration.get_voice_settings(&voice)),
+            voice,
+            previous_text: None,
+            next_text: None,
+        };
+
+        // Played once done rather than streamed, so the caller gets the same audio either way.
+        let spec = JobSpec {
+            stream_to: None,
+            ..self.job_spec(request, body.force)
+        };
+        let id = self.jobs.submit(spec);
+        self.api_jobs.insert(id);
+        if body.play {
+            self.api_playback.insert(id);
+        }
+        // Published before answering, so the caller can ask for the job right away.
+        self.publish_api_state();
+        ApiReply::json(202, json!({ "id": id, "status": JobStatus::Queued.get_name() }))
+    }
+
+    fn api_server_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("Local API:");
+        ui.checkbox(&mut self.configuration.api_server_enabled, "Let programs on this computer request speech");
+        ui.horizontal(|ui| {
+            ui.label("Port:");
+            ui.add(egui::DragValue::new(&mut self.configuration.api_server_port).range(1024..=65535));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Token:");
+            ui.text_edit_singleline(&mut self.configuration.api_server_token);
+            if ui.button("Copy").clicked() {
+                ui.output_mut(|output| output.copied_text = self.configuration.api_server_token.clone());
+            }
+            if ui.button("New token").clicked() {
+                self.configuration.api_server_token = generate_token();
+            }
+        });
+        match &self.api_server {
+            Some(server) => ui.small(format!("Listening on http://127.0.0.1:{}", server.get_port())),
+            None if self.configuration.api_server_enabled => ui.small("Starts when the settings are closed."),
+            None => ui.small("Stopped."),
+        };
+    }
+
+    fn handle_history_action(&mut self, action: HistoryAction) {
+        match action {
+            HistoryAction::Play(bytes) => {
+                self.player.play(bytes, self.output_routes());
+            }
+            HistoryAction::Resave(entry, bytes) => {
+                self.last_generated_request = Some(entry.request());
+                self.last_generated = Some(bytes);
+                self.save_last_generated();
+            }
+            HistoryAction::Regenerate(entry) => {
+                let request = entry.request();
+                let voice_id = request.voice.get_voice_id().to_string();
+                match &request.settings {
+                    Some(settings) => self.configuration.voice_settings.insert(voice_id, settings.clone()),
+                    None => self.configuration.voice_settings.remove(&voice_id),
+                };
+                self.configuration.text = request.text.clone();
+                self.configuration.voice = request.voice.clone();
+                if let Some(model) = self.models.iter().find(|model| model.get_model_id() == request.model) {
+                    self.configuration.model = model.clone();
+                }
+                self.generate_request(request, true);
+            }
+        }
+    }
+
+    fn voice_settings_ui(&mut self, ui: &mut egui::Ui) {
+        let voice_id = self.configuration.voice.get_voice_id().to_string();
+        let customized = self.configuration.voice_settings.contains_key(&voice_id);
+        let mut settings = self.configuration.voice_settings.get(&voice_id).cloned().unwrap_or_default();
+        let mut changed = false;
+
+        egui::CollapsingHeader::new("Voice settings").show(ui, |ui| {
+            changed |= settings.ui(ui, "voice_settings");
+
+            if !customized {
+                ui.small("Not tuned yet, the voice is used with its own settings.");
+            }
+
+            ui.horizontal(|ui| {
+                if ui.button("Load defaults from API").clicked() {
+                    self.load_voice_settings();
+                }
+                if ui.add_enabled(customized, egui::Button::new("Reset")).clicked() {
+                    self.configuration.voice_settings.remove(&voice_id);
+                }
+                if self.voice_settings_loading {
+                    ui.spinner();
+                }
             });
+        });
+
+        if changed {
+            self.configuration.voice_settings.insert(voice_id, settings);
+        }
+    }
+
+    /// Writes the last generation to `save_to`, converted to the configured export format.
+    pub fn save_last_generated(&mut self) {
+        let Some(bytes) = self.last_generated.clone() else {
+            return;
+        };
 
-            if let Some(voices) = voices {
-                tx.send(voices).unwrap()
+        let format = self.configuration.export_format;
+        self.configuration.save_counter += 1;
+        let name = match &self.last_generated_request {
+            Some(request) => render_template(&self.configuration.file_name_template, request, self.configuration.save_counter),
+            None => self.last_generated_file_name.clone(),
+        };
+        let path = output_path(Path::new(&self.configuration.save_to), &name, format.extension(&bytes), self.configuration.collision);
+        self.last_generated_file_path = path.display().to_string();
+        log::info!("Saving to: {}", self.last_generated_file_path);
+
+        let tx = self.error_tx.clone();
+        self.runtime.spawn_blocking(move || {
+            let result = format.export(&bytes).map_err(PleaseSpeakError::Other).and_then(|bytes| {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).map_err(|e| PleaseSpeakError::Filesystem(format!("Could not create {}: {}", dir.display(), e)))?;
+                }
+                save(&path.display().to_string(), bytes).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))
+            });
+            if let Err(error) = result {
+                let _ = tx.send_blocking((ErrorSource::Export, error));
             }
         });
     }
@@ -184,6 +1028,9 @@ impl TtsApp {
 impl eframe::App for TtsApp {
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         eframe::set_value(storage, APP_KEY, &self.configuration);
+        if let Err(error) = self.project.save_pending() {
+            self.report(ErrorSource::Project, error);
+        }
     }
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
@@ -197,6 +1044,36 @@ impl eframe::App for TtsApp {
                             ui.close_menu();
                         }
 
+                        if ui.button("History").clicked() {
+                            self.history_open = true;
+                            ui.close_menu();
+                        }
+
+                        if ui.button("Jobs").clicked() {
+                            self.jobs_open = true;
+                            ui.close_menu();
+                        }
+
+                        if ui.button("Soundboard").clicked() {
+                            self.soundboard_open = true;
+                            ui.close_menu();
+                        }
+
+                        if ui.button("Batch").clicked() {
+                            self.batch_open = true;
+                            ui.close_menu();
+                        }
+
+                        if ui.button("Project").clicked() {
+                            self.project_open = true;
+                            ui.close_menu();
+                        }
+
+                        if ui.button("Error log").clicked() {
+                            self.error_log_open = true;
+                            ui.close_menu();
+                        }
+
                         ui.separator();
 
                         if ui.button("Quit").clicked() {
@@ -207,20 +1084,45 @@ impl eframe::App for TtsApp {
                 }
 
                 egui::widgets::global_dark_light_mode_buttons(ui);
+
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    ui.label(self.connection.get_name());
+                });
             });
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.heading("Please Speak - Powered by Elenlabs");
+            ui.heading(format!("Please Speak - Powered by {}", self.provider.name()));
+
+            if self.connection != ConnectionState::Connected {
+                match self.connection.clone() {
+                    ConnectionState::Connecting => {
+                        ui.horizontal(|ui| {
+                            ui.label(format!("Connecting to {}...", self.configuration.provider.get_name()));
+                            ui.spinner();
+                        });
+                    }
+                    ConnectionState::Failed(reason) => {
+                        ui.colored_label(ui.visuals().error_fg_color, format!("Could not connect: {}", reason));
+                        if let Some(suggestion) = reason.get_suggestion() {
+                            ui.weak(suggestion);
+                        }
+                        if ui.button("Retry").clicked() {
+                            self.connect();
+                        }
+                    }
+                    _ => {
+                        ui.label("Please enter your API Key to get started.");
+                    }
+                }
 
-            if self.configuration.api_key.is_empty() || !self.elabs.connected() {
-                ui.label("Please enter your API Key to get started.");
                 ui.horizontal(|ui| {
                     ui.label("API Key:");
-                    ui.text_edit_singleline(&mut self.configuration.api_key);
-                    if ui.button("Submit").clicked() {
-                        self.elabs.init(self.configuration.api_key.clone());
-                        self.load_api_resources();
+                    ui.add(egui::TextEdit::singleline(&mut self.configuration.api_key).password(true));
+                    let connecting = self.connection == ConnectionState::Connecting;
+                    if ui.add_enabled(!connecting, egui::Button::new("Submit")).clicked() {
+                        self.store_api_key();
+                        self.connect();
                         self.security_checks();
                     }
                 });
@@ -229,19 +1131,49 @@ impl eframe::App for TtsApp {
                     ui.add_sized([ui.available_size().x, 150.], egui::TextEdit::multiline(&mut self.configuration.text));
                 });
 
+                let characters = self.configuration.text.chars().count();
+                let max_characters = self.configuration.model.get_max_characters();
+                let count = format!("{} / {} characters", characters, max_characters);
+                if max_characters > 0 && characters > max_characters {
+                    ui.colored_label(ui.visuals().error_fg_color, count);
+                } else {
+                    ui.small(count);
+                }
+
                 if self.voices_loading {
                     ui.horizontal(|ui| {
                         ui.label("Loading voices...");
                         ui.spinner();
                     });
                 } else {
-                    egui::ComboBox::from_label("Select a voice")
-                        .selected_text(format!("Voice: {}", self.configuration.voice.get_voice_name()))
-                        .show_ui(ui, |ui| {
-                            for voice in &self.voices {
-                                ui.selectable_value(&mut self.configuration.voice, voice.clone(), voice.get_voice_name());
-                            }
-                        });
+                    ui.horizontal(|ui| {
+                        egui::ComboBox::from_label("Select a voice")
+                            .selected_text(format!("Voice: {}", self.configuration.voice.get_voice_name()))
+                            .show_ui(ui, |ui| {
+                                for voice in &self.voices {
+                                    ui.selectable_value(&mut self.configuration.voice, voice.clone(), voice.get_voice_name());
+                                }
+                            });
+
+                        if self.provider.capabilities().model_selection {
+                            egui::ComboBox::from_label("Select a model")
+                                .selected_text(format!("Model: {}", self.configuration.model.get_model_name()))
+                                .show_ui(ui, |ui| {
+                                    for model in &self.models {
+                                        ui.selectable_value(&mut self.configuration.model, model.clone(), model.get_model_name())
+                                            .on_hover_text(format!(
+                                                "Up to {} characters\n{}",
+                                                model.get_max_characters(),
+                                                model.get_languages().join(", ")
+                                            ));
+                                    }
+                                });
+                        }
+                    });
+                }
+
+                if self.provider.capabilities().voice_settings {
+                    self.voice_settings_ui(ui);
                 }
                 ui.end_row();
 
@@ -249,7 +1181,23 @@ impl eframe::App for TtsApp {
                     if ui.button("Generate").clicked() {
                         self.generate();
                     }
-                    if self.generate_loading {
+                    if ui.button("History").clicked() {
+                        self.history_open = !self.history_open;
+                    }
+                    if ui.button("Soundboard").clicked() {
+                        self.soundboard_open = !self.soundboard_open;
+                    }
+                    if self.configuration.cache_enabled {
+                        ui.checkbox(&mut self.force_regenerate, "Force regenerate");
+                    }
+                    if self.provider.capabilities().streaming {
+                        ui.checkbox(&mut self.configuration.stream_playback, "Play while generating");
+                    }
+                    let active = self.jobs.active();
+                    if ui.button(format!("Jobs ({})", active)).clicked() {
+                        self.jobs_open = !self.jobs_open;
+                    }
+                    if active > 0 {
                         ui.label("Generating...");
                         ui.spinner();
                     }
@@ -258,32 +1206,27 @@ impl eframe::App for TtsApp {
                 ui.end_row();
 
                 if self.last_generated.is_some() {
-                    ui.label(self.last_generated_file_path.clone());
+                    if self.last_generated_cached {
+                        ui.label(format!("{} (from cache)", self.last_generated_file_path));
+                    } else {
+                        ui.label(self.last_generated_file_path.clone());
+                    }
                     ui.horizontal(|ui| {
-                        if ui.button("Play for me").clicked() {
-                            play(self.last_generated.as_ref().unwrap().clone());
-                        }
-
-                        if ui.button("Play").clicked() {
-                            let device = self.configuration.output_device.get_device();
-
-                            let (_stream, stream_handle) = OutputStream::try_from_device(&device).unwrap();
-
-                            let file = BufReader::new(File::open(&self.last_generated_file_path).unwrap());
-                            let source = Decoder::new(file).unwrap();
-                            let sink = Sink::try_new(&stream_handle).unwrap();
-
-                            sink.append(source);
-                            sink.sleep_until_end();
+                        let routes = self.output_routes();
+                        let can_play = !self.devices.is_empty() && !routes.is_empty();
+                        if ui.add_enabled(can_play, egui::Button::new("Play")).clicked() {
+                            self.player.play(self.last_generated.as_ref().unwrap().clone(), routes);
                         }
 
                         if ui.button("Save").clicked() {
-                            self.last_generated_file_path = format!("{}/{}", self.configuration.save_to, &self.last_generated_file_name);
-                            println!("Saving to: {}", self.last_generated_file_path);
-                            save(&self.last_generated_file_path, self.last_generated.as_ref().unwrap().clone()).unwrap();
+                            self.save_last_generated();
                         }
                     });
                 }
+
+                if self.last_generated.is_some() || self.player.state().status != PlaybackStatus::Stopped {
+                    self.player.ui(ui);
+                }
             }
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
@@ -297,51 +1240,429 @@ impl eframe::App for TtsApp {
                 .collapsible(false)
                 .resizable(false)
                 .show(ctx, |ui| {
+                    ui.label("Provider:");
+                    egui::ComboBox::from_label("Select a provider")
+                        .selected_text(self.configuration.provider.get_name())
+                        .show_ui(ui, |ui| {
+                            for provider in ProviderKind::ALL {
+                                ui.selectable_value(&mut self.configuration.provider, provider, provider.get_name());
+                            }
+                        });
+
+                    ui.separator();
+
                     ui.label("Enter your API Key:");
-                    let falsified_key = "*".repeat(self.configuration.api_key.clone().len());
-                    ui.text_edit_singleline(&mut falsified_key.to_string());
+                    ui.horizontal(|ui| {
+                        let id = egui::Id::new("settings_api_key");
+                        ui.add(egui::TextEdit::singleline(&mut self.configuration.api_key).id(id).password(!self.reveal_api_key));
+                        if ui.button(if self.reveal_api_key { "Hide" } else { "Show" }).clicked() {
+                            self.reveal_api_key = !self.reveal_api_key;
+                        }
+                        if ui.button("Paste").clicked() {
+                            // The clipboard is read by the integration and delivered to the focused field.
+                            self.configuration.api_key.clear();
+                            ui.memory_mut(|memory| memory.request_focus(id));
+                            ui.ctx().send_viewport_cmd(egui::ViewportCommand::RequestPaste);
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.add_enabled(!self.account_loading, egui::Button::new("Test connection")).clicked() {
+                            self.test_connection();
+                        }
+                        if self.account_loading {
+                            ui.spinner();
+                        }
+                    });
+                    match &self.account {
+                        Some(Ok(account)) => {
+                            ui.label(format!("Account: {}", account.name.as_deref().unwrap_or("unnamed")));
+                            ui.label(format!(
+                                "Subscription: {}, {} / {} characters used",
+                                account.tier, account.character_count, account.character_limit
+                            ));
+                            if let Some(reset) = account.next_reset.and_then(|reset| chrono::DateTime::from_timestamp(reset, 0)) {
+                                ui.small(format!("Resets on {}", reset.with_timezone(&chrono::Local).format("%Y-%m-%d")));
+                            }
+                        }
+                        Some(Err(error)) => {
+                            ui.colored_label(ui.visuals().error_fg_color, error.to_string());
+                            if let Some(suggestion) = error.get_suggestion() {
+                                ui.weak(suggestion);
+                            }
+                        }
+                        None => {}
+                    }
+
+                    ui.label("Keep the API key in:");
+                    egui::ComboBox::from_label("Select a key storage")
+                        .selected_text(self.configuration.key_storage.get_name())
+                        .show_ui(ui, |ui| {
+                            for storage in KeyStorage::ALL {
+                                ui.selectable_value(&mut self.configuration.key_storage, storage, storage.get_name());
+                            }
+                        });
+                    if self.configuration.key_storage == KeyStorage::EncryptedFile {
+                        ui.horizontal(|ui| {
+                            ui.label("Passphrase:");
+                            ui.add(egui::TextEdit::singleline(&mut self.passphrase).password(true));
+                        });
+                    }
 
                     ui.separator();
 
                     ui.label("Save to:");
                     ui.text_edit_singleline(&mut self.configuration.save_to);
+                    ui.horizontal(|ui| {
+                        ui.label("File names:");
+                        ui.text_edit_singleline(&mut self.configuration.file_name_template)
+                            .on_hover_text("{voice}, {model}, {date}, {time}, {text_slug}, {hash} and {counter} are replaced, / saves into subfolders. {counter} only counts saves from this window");
+                    });
+                    egui::ComboBox::from_label("When the file exists")
+                        .selected_text(self.configuration.collision.get_name())
+                        .show_ui(ui, |ui| {
+                            for collision in Collision::ALL {
+                                ui.selectable_value(&mut self.configuration.collision, collision, collision.get_name());
+                            }
+                        });
 
                     ui.separator();
 
-                    ui.label("Output device:");
-                    egui::ComboBox::from_label("Select a device")
-                        .selected_text(format!("Device: {}", self.configuration.output_device.get_device_name()))
+                    ui.label("Export format:");
+                    egui::ComboBox::from_label("Select a format")
+                        .selected_text(self.configuration.export_format.get_name())
                         .show_ui(ui, |ui| {
-                            for device in &self.devices {
-                                ui.selectable_value(&mut self.configuration.output_device, device.clone(), device.get_device_name());
+                            for format in ExportFormat::ALL {
+                                ui.selectable_value(&mut self.configuration.export_format, format, format.get_name());
                             }
                         });
 
+                    ui.separator();
+
+                    ui.label("Cache:");
+                    ui.checkbox(&mut self.configuration.cache_enabled, "Reuse identical generations instead of calling the API");
+                    ui.horizontal(|ui| {
+                        ui.label("Maximum size (MB):");
+                        ui.add(egui::DragValue::new(&mut self.configuration.cache_size_mb).range(1..=100_000));
+                    });
+                    ui.horizontal(|ui| {
+                        let cache = SpeechCache::new(self.configuration.cache_size_mb);
+                        let (entries, size) = cache.usage();
+                        ui.label(format!("{} entries, {:.1} MB", entries, size as f64 / (1024.0 * 1024.0)));
+                        if ui.button("Clear cache").clicked() {
+                            if let Err(error) = cache.clear() {
+                                self.report(ErrorSource::Export, error);
+                            }
+                        }
+                    });
+
+                    ui.label("History:");
+                    ui.horizontal(|ui| {
+                        ui.label("Maximum size (MB):");
+                        if ui.add(egui::DragValue::new(&mut self.configuration.history_size_mb).range(1..=100_000)).changed() {
+                            self.history.set_max_size_mb(self.configuration.history_size_mb);
+                        }
+                    });
+                    let (entries, size) = self.history.usage();
+                    ui.label(format!("{} entries, {:.1} MB, the oldest are deleted past the maximum", entries, size as f64 / (1024.0 * 1024.0)));
+
+                    ui.horizontal(|ui| {
+                        ui.label("Concurrent jobs:");
+                        ui.add(egui::DragValue::new(&mut self.configuration.max_concurrent_jobs).range(1..=8));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Attempts per request:");
+                        ui.add(egui::DragValue::new(&mut self.configuration.max_attempts).range(1..=10));
+                    });
+
+                    ui.separator();
+
+                    self.output_routes_ui(ui);
+
+                    ui.separator();
+
+                    self.api_server_ui(ui);
+
                     if ui.button("Done").clicked() {
                         self.settings_modal = false;
-                        self.elabs.init(self.configuration.api_key.clone());
-                        self.load_api_resources();
+                        self.store_api_key();
+                        self.connect_if_changed();
                         self.security_checks();
+                        self.sync_api_server(ctx);
                     }
                 });
         }
 
-        self.api_error_manager.update(ctx);
-        self.elabs_error_manager.update(ctx);
+        if self.unlock_modal {
+            egui::Window::new("Unlock API Key")
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label("The API key is kept in an encrypted file, enter your passphrase:");
+                    let response = ui.add(egui::TextEdit::singleline(&mut self.passphrase).password(true));
+                    let submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+
+                    ui.horizontal(|ui| {
+                        let unlock = ui.add_enabled(self.key_tasks == 0, egui::Button::new("Unlock"));
+                        if unlock.clicked() || (submitted && self.key_tasks == 0) {
+                            self.load_api_key();
+                        }
+                        if self.key_tasks > 0 {
+                            ui.spinner();
+                        }
+                        if ui.button("Cancel").clicked() {
+                            self.unlock_modal = false;
+                        }
+                    });
+                });
+        }
+
+        if self.error_manager.update(ctx, &mut self.error_log) {
+            self.error_log_open = true;
+        }
+        self.error_log.ui(ctx, &mut self.error_log_open);
+
+        if let Some(id) = self.jobs.ui(ctx, &mut self.jobs_open) {
+            self.job_retried(id);
+        }
+
+        match self.history.ui(ctx, &mut self.history_open) {
+            Ok(Some(action)) => self.handle_history_action(action),
+            Ok(None) => {}
+            Err(error) => {
+                self.report(ErrorSource::History, error);
+            }
+        }
+
+        while let Ok(task) = self.key_rx.try_recv() {
+            self.key_task_done(task);
+        }
+
+        while let Ok(connection) = self.connection_rx.try_recv() {
+            if connection.attempt != self.connection_attempt {
+                continue;
+            }
+
+            match connection.result {
+                Ok(provider) => {
+                    self.provider = provider;
+                    self.connection = ConnectionState::Connected;
+                    self.load_api_resources();
+                }
+                Err(reason) => self.connection = ConnectionState::Failed(reason),
+            }
+        }
 
         if let Ok(voices) = self.voices_loading_rx.try_recv() {
-            self.voices = voices;
             self.voices_loading = false;
+            match voices {
+                Ok(voices) => {
+                    self.voices = voices;
+                    self.voices_revision += 1;
+                }
+                Err(error) => self.report(ErrorSource::Api, error),
+            }
         }
 
-        if let Ok(bytes) = self.generate_loading_rx.try_recv() {
-            self.generate_loading = false;
-            self.last_generated = Some(bytes.clone());
+        if let Ok(models) = self.models_loading_rx.try_recv() {
+            // Keep the saved choice, refreshed with what the API says about it now.
+            if let Some(model) = models.iter().find(|model| model.get_model_id() == self.configuration.model.get_model_id()) {
+                self.configuration.model = model.clone();
+            }
+            self.models = models;
+        }
+
+        if let Ok(account) = self.account_loading_rx.try_recv() {
+            self.account = Some(account);
+            self.account_loading = false;
+        }
+
+        if let Ok(devices) = self.devices_loading_rx.try_recv() {
+            self.devices_loading = false;
+            match devices {
+                Ok(devices) => {
+                    self.devices_failed = false;
+                    self.update_devices(devices);
+                }
+                Err(error) => {
+                    self.update_devices(Vec::new());
+                    if !self.devices_failed {
+                        self.report(ErrorSource::Playback, error);
+                    }
+                    self.devices_failed = true;
+                }
+            }
+        }
 
-            self.last_generated_file_name = format!("{}_{}.wav", self.configuration.voice.get_voice_name(), chrono::Local::now().format("%Y-%m-%d_%H-%M-%S-%3f"));
-            self.last_generated_file_path = format!("{}/{}", env::temp_dir().display(), &self.last_generated_file_name);
-            save(&self.last_generated_file_path, bytes).unwrap();
+        if !self.devices_loading && self.devices_watched() {
+            let wait = self.devices_listed_at.map_or(Duration::ZERO, |listed_at| DEVICE_POLL_INTERVAL.saturating_sub(listed_at.elapsed()));
+            if wait.is_zero() {
+                self.refresh_devices();
+            } else {
+                ctx.request_repaint_after(wait);
+            }
+        }
+        // The list and the key storage are handled in the background, come back for them.
+        if self.devices_loading || self.key_tasks > 0 {
+            ctx.request_repaint_after(Duration::from_millis(100));
         }
+
+        if let Ok((voice_id, settings)) = self.voice_settings_loading_rx.try_recv() {
+            self.configuration.voice_settings.insert(voice_id, settings);
+            self.voice_settings_loading = false;
+        }
+
+        let template = self.current_request();
+        match self.soundboard.ui(ctx, &mut self.soundboard_open, &self.voices, &template) {
+            Ok(Some(SoundboardAction::Play(bytes))) => self.player.play(bytes, self.output_routes()),
+            Ok(Some(SoundboardAction::Render(pad))) => self.render_pad(pad, true),
+            Ok(None) => {}
+            Err(error) => {
+                self.report(ErrorSource::Soundboard, error);
+            }
+        }
+
+        match self.project.ui(ctx, &mut self.project_open, &self.voices, &template) {
+            Ok(Some(ProjectAction::Render(lines, force))) => {
+                for line in lines {
+                    self.render_line(line, force);
+                }
+            }
+            Ok(Some(ProjectAction::Play(bytes))) => self.player.play(bytes, self.output_routes()),
+            Ok(Some(ProjectAction::Export(name, bytes))) => {
+                self.last_generated_request = None;
+                self.last_generated_file_name = if name.is_empty() { "project".to_string() } else { name };
+                self.last_generated = Some(bytes);
+                self.last_generated_cached = false;
+                self.save_last_generated();
+            }
+            Ok(None) => {}
+            Err(error) => {
+                self.report(ErrorSource::Project, error);
+            }
+        }
+        self.configuration.project_path = self.project.get_path().to_string();
+
+        match self.batch.ui(ctx, &mut self.batch_open, &mut self.configuration.batch_template) {
+            Some(BatchAction::Load(path)) => self.load_batch(&path),
+            Some(BatchAction::Stop(jobs)) => {
+                for id in jobs {
+                    self.jobs.cancel(id);
+                }
+            }
+            None => {}
+        }
+        if let Err(error) = self.batch.update() {
+            self.report(ErrorSource::Batch, error);
+        }
+
+        while let Ok(call) = self.api_calls_rx.try_recv() {
+            let _ = call.reply.send(self.api_speak(call.body));
+        }
+        for id in self.api_server.as_ref().map(ApiServer::fetched).unwrap_or_default() {
+            if self.api_jobs.remove(&id) {
+                self.jobs.release(id);
+            }
+        }
+
+        for job in self.jobs.update(&self.provider, &self.player, self.configuration.max_concurrent_jobs) {
+            // Batch, soundboard and project failures are shown in their own windows.
+            match self.batch.job_finished(&job, self.configuration.export_format, self.configuration.collision) {
+                Ok(true) => {
+                    self.jobs.release(job.id);
+                    continue;
+                }
+                Ok(false) => {}
+                Err(error) => {
+                    self.report(ErrorSource::Batch, error);
+                    continue;
+                }
+            }
+
+            // Failed jobs keep their owner in case they are retried from the jobs window.
+            if let Some(&pad) = self.soundboard_jobs.get(&job.id) {
+                match (&job.status, job.result) {
+                    (JobStatus::Done, Some(result)) => {
+                        self.soundboard_jobs.remove(&job.id);
+                        self.jobs.release(job.id);
+                        if let Some(bytes) = self.soundboard.rendered(pad, job.spec.request, result.bytes) {
+                            self.player.play(bytes, self.output_routes());
+                        }
+                    }
+                    (status, _) => {
+                        let error = match status {
+                            JobStatus::Failed(error) => error.to_string(),
+                            status => status.get_name().to_string(),
+                        };
+                        self.soundboard.render_failed(pad, job.spec.request, error);
+                    }
+                }
+                continue;
+            }
+
+            if let Some(&line) = self.project_jobs.get(&job.id) {
+                match (&job.status, job.result) {
+                    (JobStatus::Done, Some(result)) => {
+                        self.project_jobs.remove(&job.id);
+                        self.jobs.release(job.id);
+                        self.project.rendered(line, job.spec.request, result.bytes);
+                    }
+                    (status, _) => {
+                        let error = match status {
+                            JobStatus::Failed(error) => error.to_string(),
+                            status => status.get_name().to_string(),
+                        };
+                        self.project.render_failed(line, error);
+                    }
+                }
+                continue;
+            }
+
+            let play = self.api_playback.remove(&job.id);
+            let (JobStatus::Done, Some(result)) = (&job.status, job.result) else {
+                // Local API clients read the failure from the job, the jobs window lets them be retried.
+                if let JobStatus::Failed(error) = job.status {
+                    if !self.api_jobs.contains(&job.id) {
+                        self.report(ErrorSource::Api, error);
+                    }
+                }
+                self.jobs_open = true;
+                continue;
+            };
+
+            self.last_generated = Some(result.bytes.clone());
+            self.last_generated_cached = result.cached;
+            self.last_generated_request = Some(job.spec.request.clone());
+            if play {
+                self.player.play(result.bytes.clone(), self.output_routes());
+            }
+
+            match self.history.record(&job.spec.request, &result.bytes, result.duration) {
+                Ok(entry) => self.last_generated_file_path = entry.file_path,
+                Err(error) => {
+                    self.last_generated_file_path = "".to_string();
+                    self.report(ErrorSource::History, error);
+                }
+            }
+            // The history and the save button have their copy now.
+            if !self.api_jobs.contains(&job.id) {
+                self.jobs.release(job.id);
+            }
+        }
+
+        // Jobs cleared from the jobs window can no longer be retried.
+        let jobs = &self.jobs;
+        self.soundboard_jobs.retain(|id, _| jobs.get(*id).is_some());
+        self.project_jobs.retain(|id, _| jobs.get(*id).is_some());
+        self.api_jobs.retain(|id| jobs.get(*id).is_some());
+
+        if self.provider.connected() {
+            for pad in self.soundboard.unrendered() {
+                self.render_pad(pad, false);
+            }
+            self.submit_batch_rows();
+        }
+
+        self.publish_api_state();
     }
 }
 
@@ -0,0 +1,590 @@
+use std::io::Cursor;
+use std::num::{NonZeroU32, NonZeroU8};
+use std::time::Duration;
+use elevenlabs_rs::Bytes;
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+use vorbis_rs::VorbisEncoderBuilder;
+
+/// Container of an audio clip, detected from its first bytes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AudioFormat {
+    Mp3,
+    Wav,
+    Flac,
+    Ogg,
+}
+
+impl AudioFormat {
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"ID3") || (bytes.len() > 1 && bytes[0] == 0xFF && bytes[1] & 0xE0 == 0xE0) {
+            Some(AudioFormat::Mp3)
+        } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WAVE" {
+            Some(AudioFormat::Wav)
+        } else if bytes.starts_with(b"fLaC") {
+            Some(AudioFormat::Flac)
+        } else if bytes.starts_with(b"OggS") {
+            Some(AudioFormat::Ogg)
+        } else {
+            None
+        }
+    }
+
+    /// Container of the audio a provider sends for one of its output formats, like `mp3_44100_128`.
+    ///
+    /// Raw formats such as `pcm_16000` have none.
+    pub fn from_output_format(output_format: &str) -> Option<Self> {
+        output_format.starts_with("mp3_").then_some(AudioFormat::Mp3)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Ogg => "ogg",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Flac => "audio/flac",
+            AudioFormat::Ogg => "audio/ogg",
+        }
+    }
+}
+
+/// Extension to use for raw bytes, `bin` when the container is unknown.
+pub fn extension_of(bytes: &[u8]) -> &'static str {
+    AudioFormat::sniff(bytes).map_or("bin", |format| format.extension())
+}
+
+/// Length of a clip, decoding it whole for formats whose header does not tell it.
+pub fn duration_of(bytes: &Bytes) -> Option<Duration> {
+    let source = Decoder::new(Cursor::new(bytes.clone())).ok()?;
+    if let Some(duration) = source.total_duration() {
+        return Some(duration);
+    }
+
+    let channels = source.channels() as u64;
+    let sample_rate = source.sample_rate() as u64;
+    if channels == 0 || sample_rate == 0 {
+        return None;
+    }
+
+    let samples = source.count() as u64;
+    Some(Duration::from_secs_f64(samples as f64 / (channels * sample_rate) as f64))
+}
+
+/// Format files are written in when saving.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum ExportFormat {
+    /// Keep the bytes returned by the provider untouched.
+    #[default]
+    Original,
+    Wav,
+    Flac,
+    OggVorbis,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 4] = [ExportFormat::Original, ExportFormat::Wav, ExportFormat::Flac, ExportFormat::OggVorbis];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            ExportFormat::Original => "Original (no conversion)",
+            ExportFormat::Wav => "WAV",
+            ExportFormat::Flac => "FLAC",
+            ExportFormat::OggVorbis => "Ogg Vorbis",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "original" | "mp3" => Some(ExportFormat::Original),
+            "wav" => Some(ExportFormat::Wav),
+            "flac" => Some(ExportFormat::Flac),
+            "ogg" | "vorbis" => Some(ExportFormat::OggVorbis),
+            _ => None,
+        }
+    }
+
+    /// Extension of the file [`Self::export`] produces from `bytes`.
+    pub fn extension(&self, bytes: &[u8]) -> &'static str {
+        match self {
+            ExportFormat::Original => extension_of(bytes),
+            ExportFormat::Wav => AudioFormat::Wav.extension(),
+            ExportFormat::Flac => AudioFormat::Flac.extension(),
+            ExportFormat::OggVorbis => AudioFormat::Ogg.extension(),
+        }
+    }
+
+    /// Converts `bytes` to this format, decoding them first when needed.
+    pub fn export(&self, bytes: &Bytes) -> Result<Bytes, String> {
+        match self {
+            ExportFormat::Original => Ok(bytes.clone()),
+            ExportFormat::Wav => Ok(DecodedAudio::decode(bytes)?.to_wav()?.into()),
+            ExportFormat::Flac => Ok(DecodedAudio::decode(bytes)?.to_flac().into()),
+            ExportFormat::OggVorbis => Ok(DecodedAudio::decode(bytes)?.to_ogg_vorbis()?.into()),
+        }
+    }
+}
+
+/// Interleaved 16 bit PCM samples.
+#[derive(Clone, Debug)]
+pub struct DecodedAudio {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<i16>,
+}
+
+impl DecodedAudio {
+    pub fn decode(bytes: &Bytes) -> Result<Self, String> {
+        let decoder = Decoder::new(Cursor::new(bytes.clone())).map_err(|e| format!("Decoder Error: {}", e))?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        if channels == 0 || sample_rate == 0 {
+            return Err("Decoder Error: the audio has no channels".to_string());
+        }
+
+        Ok(Self {
+            channels,
+            sample_rate,
+            samples: decoder.collect(),
+        })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    pub fn append_silence(&mut self, duration: Duration) {
+        let frames = (self.sample_rate as f32 * duration.as_secs_f32()) as usize;
+        self.samples.resize(self.samples.len() + frames * self.channels as usize, 0);
+    }
+
+    /// Joins `parts` end to end, overlapping each boundary by `crossfade` with a linear fade.
+    pub fn concat(parts: Vec<DecodedAudio>, crossfade: Duration) -> Result<Self, String> {
+        let mut parts = parts.into_iter();
+        let Some(mut joined) = parts.next() else {
+            return Err("Stitch Error: nothing to join".to_string());
+        };
+
+        for part in parts {
+            if part.channels != joined.channels || part.sample_rate != joined.sample_rate {
+                return Err("Stitch Error: the segments do not share the same format".to_string());
+            }
+
+            let channels = joined.channels as usize;
+            let fade = ((joined.sample_rate as f32 * crossfade.as_secs_f32()) as usize)
+                .min(joined.frames())
+                .min(part.frames());
+            let start = joined.samples.len() - fade * channels;
+
+            for frame in 0..fade {
+                let gain = (frame + 1) as f32 / (fade + 1) as f32;
+                for channel in 0..channels {
+                    let index = frame * channels + channel;
+                    let outgoing = joined.samples[start + index] as f32 * (1.0 - gain);
+                    let incoming = part.samples[index] as f32 * gain;
+                    joined.samples[start + index] = (outgoing + incoming).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                }
+            }
+            joined.samples.extend_from_slice(&part.samples[fade * channels..]);
+        }
+
+        Ok(joined)
+    }
+
+    pub fn to_wav(&self) -> Result<Vec<u8>, String> {
+        let spec = hound::WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).map_err(|e| format!("WAV Error: {}", e))?;
+        for sample in &self.samples {
+            writer.write_sample(*sample).map_err(|e| format!("WAV Error: {}", e))?;
+        }
+        writer.finalize().map_err(|e| format!("WAV Error: {}", e))?;
+
+        Ok(cursor.into_inner())
+    }
+
+    pub fn to_ogg_vorbis(&self) -> Result<Vec<u8>, String> {
+        let sample_rate = NonZeroU32::new(self.sample_rate).ok_or("Vorbis Error: invalid sample rate")?;
+        let channels = u8::try_from(self.channels).ok().and_then(NonZeroU8::new).ok_or("Vorbis Error: too many channels")?;
+
+        let mut encoder = VorbisEncoderBuilder::new(sample_rate, channels, Vec::new())
+            .and_then(|mut builder| builder.build())
+            .map_err(|e| format!("Vorbis Error: {}", e))?;
+
+        let channels = self.channels as usize;
+        for block in self.samples.chunks(1024 * channels) {
+            let planar: Vec<Vec<f32>> = (0..channels)
+                .map(|channel| block.iter().skip(channel).step_by(channels).map(|s| *s as f32 / 32768.0).collect())
+                .collect();
+            encoder.encode_audio_block(&planar).map_err(|e| format!("Vorbis Error: {}", e))?;
+        }
+
+        encoder.finish().map_err(|e| format!("Vorbis Error: {}", e))
+    }
+
+    /// Encodes to FLAC using the fixed predictors, which is lossless and close enough to
+    /// reference encoders on speech without needing a native library.
+    pub fn to_flac(&self) -> Vec<u8> {
+        const BLOCK_SIZE: usize = 4096;
+
+        let channels = self.channels as usize;
+        let frames = self.frames();
+
+        let mut out = BitWriter::default();
+        out.write_bytes(b"fLaC");
+
+        // STREAMINFO, the only metadata block.
+        out.write(1, 1);
+        out.write(0, 7);
+        out.write(34, 24);
+        let block_size = BLOCK_SIZE.min(frames.max(16)) as u64;
+        out.write(block_size, 16);
+        out.write(block_size, 16);
+        out.write(0, 24);
+        out.write(0, 24);
+        out.write(self.sample_rate as u64, 20);
+        out.write(channels as u64 - 1, 3);
+        out.write(15, 5);
+        out.write(frames as u64, 36);
+        out.write_bytes(&[0; 16]);
+
+        let mut output = out.finish();
+        for (index, block) in self.samples.chunks(BLOCK_SIZE * channels).enumerate() {
+            output.extend(encode_flac_frame(index as u64, block, channels));
+        }
+
+        output
+    }
+}
+
+fn encode_flac_frame(index: u64, block: &[i16], channels: usize) -> Vec<u8> {
+    let length = block.len() / channels;
+
+    let mut frame = BitWriter::default();
+    frame.write(0b11111111111110, 14);
+    frame.write(0, 1);
+    frame.write(0, 1);
+    frame.write(0b0111, 4);
+    frame.write(0b0000, 4);
+    frame.write(channels as u64 - 1, 4);
+    frame.write(0b100, 3);
+    frame.write(0, 1);
+    frame.write_bytes(&utf8_number(index));
+    frame.write(length as u64 - 1, 16);
+    let crc = crc8(&frame.bytes);
+    frame.write(crc as u64, 8);
+
+    for channel in 0..channels {
+        let samples: Vec<i64> = block.iter().skip(channel).step_by(channels).map(|s| *s as i64).collect();
+        encode_flac_subframe(&mut frame, &samples);
+    }
+
+    let mut bytes = frame.finish();
+    let crc = crc16(&bytes);
+    bytes.extend(crc.to_be_bytes());
+    bytes
+}
+
+fn encode_flac_subframe(out: &mut BitWriter, samples: &[i64]) {
+    let mut best: Option<(usize, Vec<u64>, u32, u64)> = None;
+    for order in 0..=4.min(samples.len().saturating_sub(1)) {
+        let residuals: Vec<u64> = (order..samples.len()).map(|n| zigzag(fixed_residual(samples, n, order))).collect();
+        let (parameter, bits) = rice_parameter(&residuals);
+        if best.as_ref().map_or(true, |(_, _, _, best_bits)| bits < *best_bits) {
+            best = Some((order, residuals, parameter, bits));
+        }
+    }
+
+    match best {
+        Some((order, residuals, parameter, bits)) if bits + (order as u64) * 16 < samples.len() as u64 * 16 => {
+            out.write(0, 1);
+            out.write(0b001000 | order as u64, 6);
+            out.write(0, 1);
+            for sample in &samples[..order] {
+                out.write(*sample as u64 & 0xFFFF, 16);
+            }
+
+            out.write(0b00, 2);
+            out.write(0, 4);
+            out.write(parameter as u64, 4);
+            for residual in residuals {
+                out.write_unary(residual >> parameter);
+                out.write(residual & ((1 << parameter) - 1), parameter);
+            }
+        }
+        _ => {
+            out.write(0, 1);
+            out.write(0b000001, 6);
+            out.write(0, 1);
+            for sample in samples {
+                out.write(*sample as u64 & 0xFFFF, 16);
+            }
+        }
+    }
+}
+
+fn fixed_residual(samples: &[i64], n: usize, order: usize) -> i64 {
+    match order {
+        0 => samples[n],
+        1 => samples[n] - samples[n - 1],
+        2 => samples[n] - 2 * samples[n - 1] + samples[n - 2],
+        3 => samples[n] - 3 * samples[n - 1] + 3 * samples[n - 2] - samples[n - 3],
+        _ => samples[n] - 4 * samples[n - 1] + 6 * samples[n - 2] - 4 * samples[n - 3] + samples[n - 4],
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Picks the Rice parameter around the mean residual and returns it with the encoded size.
+fn rice_parameter(residuals: &[u64]) -> (u32, u64) {
+    let sum: u64 = residuals.iter().sum();
+    let mean = sum / residuals.len().max(1) as u64;
+    let estimate = if mean == 0 { 0 } else { (63 - mean.leading_zeros()).min(14) };
+
+    (estimate.saturating_sub(1)..=(estimate + 1).min(14))
+        .map(|parameter| {
+            let bits = residuals.iter().map(|r| (r >> parameter) + 1 + parameter as u64).sum();
+            (parameter, bits)
+        })
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap_or((0, 0))
+}
+
+fn utf8_number(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+
+    let mut continuation = Vec::new();
+    let mut rest = value;
+    let mut first_bits = 6;
+    while rest >= 1 << first_bits {
+        continuation.push(0x80 | (rest & 0x3F) as u8);
+        rest >>= 6;
+        first_bits -= 1;
+    }
+
+    let prefix = !(0xFFu8 >> (continuation.len() + 1));
+    let mut bytes = vec![prefix | rest as u8];
+    bytes.extend(continuation.into_iter().rev());
+    bytes
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |mut crc, byte| {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    pending: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for bit in (0..bits).rev() {
+            self.accumulator = (self.accumulator << 1) | ((value >> bit) & 1);
+            self.pending += 1;
+            if self.pending == 8 {
+                self.bytes.push(self.accumulator as u8);
+                self.accumulator = 0;
+                self.pending = 0;
+            }
+        }
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        for _ in 0..zeros {
+            self.write(0, 1);
+        }
+        self.write(1, 1);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u64, 8);
+        }
+    }
+
+    /// Pads the last byte with zeros and returns everything written.
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending > 0 {
+            let pending = self.pending;
+            self.write(0, 8 - pending);
+        }
+        self.bytes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chirp with a little noise and the extreme values, so every predictor order gets used.
+    fn test_audio(channels: u16, frames: usize) -> DecodedAudio {
+        let mut noise = 0x1234_5678u32;
+        let mut samples = Vec::with_capacity(frames * channels as usize);
+        for frame in 0..frames {
+            for channel in 0..channels {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let t = frame as f32 / 22_050.0;
+                let tone = (t * (200.0 + 40.0 * frame as f32 / 100.0 + 50.0 * channel as f32) * std::f32::consts::TAU).sin() * 12_000.0;
+                samples.push((tone + (noise >> 24) as f32 - 128.0) as i16);
+            }
+        }
+        if let Some(first) = samples.first_mut() {
+            *first = i16::MIN;
+        }
+        if let Some(last) = samples.last_mut() {
+            *last = i16::MAX;
+        }
+
+        DecodedAudio { channels, sample_rate: 22_050, samples }
+    }
+
+    fn decode(bytes: Vec<u8>) -> DecodedAudio {
+        DecodedAudio::decode(&Bytes::from(bytes)).unwrap()
+    }
+
+    const SHAPES: [(u16, usize); 6] = [(1, 1), (1, 10), (2, 15), (1, 4096), (2, 5000), (1, 10_000)];
+
+    #[test]
+    fn flac_round_trips() {
+        for (channels, frames) in SHAPES {
+            let audio = test_audio(channels, frames);
+            let flac = audio.to_flac();
+            assert_eq!(AudioFormat::sniff(&flac), Some(AudioFormat::Flac));
+
+            let decoded = decode(flac);
+            assert_eq!((decoded.channels, decoded.sample_rate), (channels, 22_050), "{} channels, {} frames", channels, frames);
+            assert!(decoded.samples == audio.samples, "{} channels, {} frames", channels, frames);
+        }
+    }
+
+    /// Full scale white noise and a square wave between the extremes, whose residuals need the
+    /// largest Rice parameter or do not fit in it at all.
+    fn loud_audio(channels: u16, frames: usize) -> DecodedAudio {
+        let mut noise = 0x9E37_79B9u32;
+        let samples = (0..frames * channels as usize)
+            .map(|index| {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                match index / channels as usize % 2048 < 1024 {
+                    true => (noise >> 16) as u16 as i16,
+                    false if index / channels as usize % 2 == 0 => i16::MAX,
+                    false => i16::MIN,
+                }
+            })
+            .collect();
+
+        DecodedAudio { channels, sample_rate: 44_100, samples }
+    }
+
+    #[test]
+    fn flac_round_trips_loud_noise() {
+        for (channels, frames) in [(1, 4095), (1, 4096), (1, 4097), (2, 8192), (2, 12_289), (1, 3 * 4096 + 17)] {
+            let audio = loud_audio(channels, frames);
+            let decoded = decode(audio.to_flac());
+            assert_eq!((decoded.channels, decoded.sample_rate), (channels, 44_100), "{} channels, {} frames", channels, frames);
+            assert!(decoded.samples == audio.samples, "{} channels, {} frames", channels, frames);
+        }
+    }
+
+    #[test]
+    fn flac_round_trips_around_block_boundaries() {
+        for (channels, frames) in [(1, 4095), (2, 4096), (1, 4097), (2, 2 * 4096), (1, 2 * 4096 + 1), (2, 5 * 4096 - 1)] {
+            let audio = test_audio(channels, frames);
+            let decoded = decode(audio.to_flac());
+            assert_eq!(decoded.frames(), frames);
+            assert!(decoded.samples == audio.samples, "{} channels, {} frames", channels, frames);
+        }
+    }
+
+    #[test]
+    fn rice_parameter_stays_in_four_bits() {
+        assert_eq!(rice_parameter(&[0; 64]), (0, 64));
+        assert_eq!(rice_parameter(&[]), (0, 0));
+
+        let (parameter, bits) = rice_parameter(&[u32::MAX as u64; 8]);
+        assert_eq!(parameter, 14);
+        assert_eq!(bits, 8 * ((u32::MAX as u64 >> 14) + 1 + 14));
+
+        for residuals in [vec![1, 2, 3, 40_000], vec![1 << 20; 16], vec![7; 100]] {
+            let (parameter, bits) = rice_parameter(&residuals);
+            assert!(parameter <= 14);
+            assert_eq!(bits, residuals.iter().map(|r| (r >> parameter) + 1 + parameter as u64).sum::<u64>());
+        }
+    }
+
+    #[test]
+    fn wav_round_trips() {
+        for (channels, frames) in SHAPES {
+            let audio = test_audio(channels, frames);
+            let wav = audio.to_wav().unwrap();
+            assert_eq!(AudioFormat::sniff(&wav), Some(AudioFormat::Wav));
+
+            let decoded = decode(wav);
+            assert_eq!((decoded.channels, decoded.sample_rate), (channels, 22_050));
+            assert!(decoded.samples == audio.samples, "{} channels, {} frames", channels, frames);
+        }
+    }
+
+    #[test]
+    fn ogg_vorbis_keeps_format_and_length() {
+        for (channels, frames) in [(1, 4096), (2, 10_000)] {
+            let audio = test_audio(channels, frames);
+            let ogg = audio.to_ogg_vorbis().unwrap();
+            assert_eq!(AudioFormat::sniff(&ogg), Some(AudioFormat::Ogg));
+
+            // Vorbis is lossy, only the format and the length survive exactly enough to compare.
+            let decoded = decode(ogg);
+            assert_eq!((decoded.channels, decoded.sample_rate), (channels, 22_050));
+            assert!(decoded.frames().abs_diff(frames) < 1024, "{} frames decoded from {}", decoded.frames(), frames);
+        }
+    }
+
+    #[test]
+    fn sniff_detects_containers() {
+        assert_eq!(AudioFormat::sniff(b"ID3\x04\x00"), Some(AudioFormat::Mp3));
+        assert_eq!(AudioFormat::sniff(&[0xFF, 0xFB, 0x90, 0xC4]), Some(AudioFormat::Mp3));
+        assert_eq!(AudioFormat::sniff(b"RIFF\x24\x00\x00\x00WAVEfmt "), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::sniff(b"RIFF\x24\x00\x00\x00AVI "), None);
+        assert_eq!(AudioFormat::sniff(b"fLaC\x00"), Some(AudioFormat::Flac));
+        assert_eq!(AudioFormat::sniff(b"OggS\x00"), Some(AudioFormat::Ogg));
+        assert_eq!(AudioFormat::sniff(b"{\"detail\": \"quota\"}"), None);
+        assert_eq!(AudioFormat::sniff(b""), None);
+        assert_eq!(extension_of(b"<html>"), "bin");
+    }
+}
@@ -0,0 +1,650 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::runtime::Handle;
+use crate::audio::ExportFormat;
+use crate::errors::PleaseSpeakError;
+use crate::jobs::{Job, JobStatus};
+use crate::naming::{fill_tokens, output_path, sanitize_file_name, Collision};
+use crate::Voice;
+
+pub const DEFAULT_BATCH_TEMPLATE: &str = "{id}_{voice}";
+
+/// A line of a script, as read from the file.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct BatchRow {
+    pub id: String,
+    /// Id or name of the voice, the voice selected in the app when empty.
+    pub voice: String,
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum RowStatus {
+    Pending,
+    Rendering,
+    Writing,
+    /// Written to this path.
+    Done(String),
+    Failed(String),
+}
+
+impl RowStatus {
+    pub fn get_name(&self) -> &str {
+        match self {
+            RowStatus::Pending => "Pending",
+            RowStatus::Rendering => "Rendering",
+            RowStatus::Writing => "Writing",
+            RowStatus::Done(_) => "Done",
+            RowStatus::Failed(_) => "Failed",
+        }
+    }
+}
+
+pub struct BatchItem {
+    pub row: BatchRow,
+    /// `None` when no voice has the id or name of the row.
+    pub voice: Option<Voice>,
+    /// Name of the output file, without extension.
+    pub file_name: String,
+    pub status: RowStatus,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ManifestOutput {
+    pub id: String,
+    pub voice: String,
+    pub text: String,
+    pub file: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ManifestFailure {
+    pub id: String,
+    pub voice: String,
+    pub text: String,
+    pub error: String,
+}
+
+/// What a batch produced, written next to the outputs after each row so an interrupted batch
+/// resumes where it stopped.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+pub struct Manifest {
+    pub source: String,
+    pub outputs: Vec<ManifestOutput>,
+    pub failures: Vec<ManifestFailure>,
+}
+
+/// What the user asked for from the batch window, handled by the app.
+pub enum BatchAction {
+    Load(String),
+    /// Cancels the jobs rendering these rows, they are pending again.
+    Stop(Vec<u64>),
+}
+
+/// Renders every line of a CSV or JSONL script to its own file through the job queue.
+pub struct Batch {
+    runtime: Handle,
+    source: String,
+    items: Vec<BatchItem>,
+    /// Submits pending rows while set.
+    running: bool,
+    /// Rows being rendered, by job.
+    jobs: HashMap<u64, usize>,
+    /// Rows of failed or cancelled jobs, by job, in case they are retried from the jobs window.
+    /// `None` once another script was loaded.
+    retryable: HashMap<u64, Option<usize>>,
+    dir: PathBuf,
+    manifest_path: Option<PathBuf>,
+    writes_tx: Sender<(usize, Result<String, String>)>,
+    writes_rx: Receiver<(usize, Result<String, String>)>,
+}
+
+impl Batch {
+    pub fn new(runtime: Handle) -> Self {
+        let (writes_tx, writes_rx) = channel();
+
+        Self {
+            runtime,
+            source: String::new(),
+            items: Vec::new(),
+            running: false,
+            jobs: HashMap::new(),
+            retryable: HashMap::new(),
+            dir: PathBuf::new(),
+            manifest_path: None,
+            writes_tx,
+            writes_rx,
+        }
+    }
+
+    /// Reads the script at `path` and maps its rows to `voices`, rows already in the manifest
+    /// of an earlier run are kept as done.
+    pub fn load(&mut self, path: &str, voices: &[Voice], fallback: &Voice, dir: &str, template: &str) -> Result<(), PleaseSpeakError> {
+        if self.running || !self.jobs.is_empty() {
+            return Err(PleaseSpeakError::Other("Stop the running batch first".to_string()));
+        }
+
+        let rows = read_rows(Path::new(path))?;
+        self.retryable.values_mut().for_each(|index| *index = None);
+        let dir = PathBuf::from(dir);
+        let stem = Path::new(path).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_else(|| "batch".to_string());
+        let manifest_path = dir.join(format!("{}_manifest.json", sanitize_file_name(&stem)));
+        let manifest: Manifest = fs::read_to_string(&manifest_path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        self.items = rows.into_iter()
+            .map(|row| {
+                let voice = if row.voice.is_empty() {
+                    Some(fallback.clone())
+                } else {
+                    voices.iter()
+                        .find(|voice| voice.get_voice_id() == row.voice || voice.get_voice_name().eq_ignore_ascii_case(&row.voice))
+                        .cloned()
+                };
+                let done = manifest.outputs.iter()
+                    .find(|output| output.id == row.id && output.voice == row.voice && output.text == row.text && Path::new(&output.file).exists());
+                let status = match (done, &voice) {
+                    (Some(output), _) => RowStatus::Done(output.file.clone()),
+                    (None, None) => RowStatus::Failed(format!("No voice with the id or name \"{}\"", row.voice)),
+                    (None, Some(_)) => RowStatus::Pending,
+                };
+                BatchItem { row, voice, file_name: String::new(), status }
+            })
+            .collect();
+        self.source = path.to_string();
+        self.dir = dir;
+        self.manifest_path = Some(manifest_path);
+        self.assign_file_names(template);
+        self.save_manifest()
+    }
+
+    /// Names the files of the rows left from `template`, numbering names used twice.
+    fn assign_file_names(&mut self, template: &str) {
+        let mut used: HashSet<String> = self.items.iter()
+            .filter_map(|item| match &item.status {
+                RowStatus::Done(_) | RowStatus::Writing => Some(item.file_name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        for (index, item) in self.items.iter_mut().enumerate() {
+            if matches!(item.status, RowStatus::Done(_) | RowStatus::Writing) {
+                continue;
+            }
+
+            let voice = item.voice.as_ref().map_or(item.row.voice.as_str(), |voice| voice.get_voice_name());
+            let mut name = fill_tokens(template, &[
+                ("{id}", item.row.id.clone()),
+                ("{voice}", voice.to_string()),
+                ("{index}", (index + 1).to_string()),
+            ]);
+            if used.contains(&name) {
+                name = format!("{}_{}", name, index + 1);
+            }
+            used.insert(name.clone());
+            item.file_name = name;
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Jobs rendering rows now.
+    pub fn in_flight(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Takes the next pending row to render, with its voice, while the batch runs.
+    pub fn next_pending(&mut self) -> Option<(usize, Voice, String)> {
+        if !self.running {
+            return None;
+        }
+
+        let Some((index, item)) = self.items.iter_mut().enumerate().find(|(_, item)| item.status == RowStatus::Pending) else {
+            self.running = false;
+            return None;
+        };
+        item.status = RowStatus::Rendering;
+        Some((index, item.voice.clone()?, item.row.text.clone()))
+    }
+
+    pub fn submitted(&mut self, job: u64, index: usize) {
+        self.jobs.insert(job, index);
+    }
+
+    /// Takes the result of a job rendering a row, writing its audio in `format`. Returns
+    /// `false` for jobs of something else.
+    pub fn job_finished(&mut self, job: &Job, format: ExportFormat, collision: Collision) -> Result<bool, PleaseSpeakError> {
+        let Some(index) = self.jobs.remove(&job.id) else {
+            return Ok(false);
+        };
+        if job.status != JobStatus::Done {
+            self.retryable.insert(job.id, Some(index));
+        }
+        let Some(item) = self.items.get_mut(index) else {
+            return Ok(true);
+        };
+
+        match (&job.status, &job.result) {
+            (JobStatus::Done, Some(result)) => {
+                item.status = RowStatus::Writing;
+                let bytes = result.bytes.clone();
+                let dir = self.dir.clone();
+                let name = item.file_name.clone();
+                let tx = self.writes_tx.clone();
+                self.runtime.spawn_blocking(move || {
+                    let result = format.export(&bytes).and_then(|exported| {
+                        let path = output_path(&dir, &name, format.extension(&bytes), collision);
+                        if let Some(folder) = path.parent() {
+                            fs::create_dir_all(folder).map_err(|e| e.to_string())?;
+                        }
+                        fs::write(&path, exported).map(|_| path.display().to_string()).map_err(|e| e.to_string())
+                    });
+                    let _ = tx.send((index, result));
+                });
+                Ok(true)
+            }
+            (JobStatus::Cancelled, _) => {
+                item.status = RowStatus::Pending;
+                Ok(true)
+            }
+            (status, _) => {
+                item.status = RowStatus::Failed(match status {
+                    JobStatus::Failed(error) => error.to_string(),
+                    status => status.get_name().to_string(),
+                });
+                self.save_manifest().map(|_| true)
+            }
+        }
+    }
+
+    /// Takes back a job queued again from the jobs window, `None` for jobs of something else.
+    ///
+    /// `Some(false)` when its row was rendered by another job meanwhile, so it is not needed.
+    pub fn retried(&mut self, job: u64) -> Option<bool> {
+        let index = self.retryable.remove(&job)?
+            .filter(|index| matches!(self.items.get(*index).map(|item| &item.status), Some(RowStatus::Pending | RowStatus::Failed(_))));
+        let Some(index) = index else {
+            return Some(false);
+        };
+
+        self.items[index].status = RowStatus::Rendering;
+        self.jobs.insert(job, index);
+        Some(true)
+    }
+
+    /// Applies the files written since the last call.
+    pub fn update(&mut self) -> Result<(), PleaseSpeakError> {
+        let mut changed = false;
+        while let Ok((index, result)) = self.writes_rx.try_recv() {
+            if let Some(item) = self.items.get_mut(index) {
+                item.status = match result {
+                    Ok(path) => RowStatus::Done(path),
+                    Err(error) => RowStatus::Failed(format!("Could not write the file: {}", error)),
+                };
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.save_manifest()?;
+        }
+        Ok(())
+    }
+
+    fn manifest(&self) -> Manifest {
+        let mut manifest = Manifest {
+            source: self.source.clone(),
+            ..Manifest::default()
+        };
+        for item in &self.items {
+            match &item.status {
+                RowStatus::Done(file) => manifest.outputs.push(ManifestOutput {
+                    id: item.row.id.clone(),
+                    voice: item.row.voice.clone(),
+                    text: item.row.text.clone(),
+                    file: file.clone(),
+                }),
+                RowStatus::Failed(error) => manifest.failures.push(ManifestFailure {
+                    id: item.row.id.clone(),
+                    voice: item.row.voice.clone(),
+                    text: item.row.text.clone(),
+                    error: error.clone(),
+                }),
+                _ => {}
+            }
+        }
+        manifest
+    }
+
+    fn save_manifest(&self) -> Result<(), PleaseSpeakError> {
+        let Some(path) = &self.manifest_path else {
+            return Ok(());
+        };
+
+        fs::create_dir_all(&self.dir).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+        let content = serde_json::to_string_pretty(&self.manifest()).map_err(|e| PleaseSpeakError::Other(e.to_string()))?;
+        fs::write(path, content).map_err(|e| PleaseSpeakError::Filesystem(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Draws the batch window and returns the action the user clicked.
+    pub fn ui(&mut self, ctx: &egui::Context, open: &mut bool, template: &mut String) -> Option<BatchAction> {
+        let mut action = None;
+
+        if self.running || !self.jobs.is_empty() {
+            ctx.request_repaint_after(std::time::Duration::from_millis(250));
+        }
+
+        egui::Window::new("Batch")
+            .open(open)
+            .default_size([560.0, 360.0])
+            .show(ctx, |ui| {
+                let idle = !self.running && self.jobs.is_empty();
+
+                ui.horizontal(|ui| {
+                    ui.label("Script:");
+                    ui.add_enabled(idle, egui::TextEdit::singleline(&mut self.source).hint_text("lines.csv or lines.jsonl"));
+                    if ui.add_enabled(idle && !self.source.is_empty(), egui::Button::new("Load")).clicked() {
+                        action = Some(BatchAction::Load(self.source.clone()));
+                    }
+                });
+                ui.small("Rows need an id, a voice (id or name) and a text, CSV files with a header naming these columns.");
+
+                ui.horizontal(|ui| {
+                    ui.label("File names:");
+                    if ui.add_enabled(idle, egui::TextEdit::singleline(template)).changed() {
+                        self.assign_file_names(template);
+                    }
+                });
+                ui.small("{id}, {voice} and {index} are replaced, the extension follows the export format.");
+
+                ui.separator();
+
+                let total = self.items.len();
+                let done = self.items.iter().filter(|item| matches!(item.status, RowStatus::Done(_))).count();
+                let failed = self.items.iter().filter(|item| matches!(item.status, RowStatus::Failed(_))).count();
+                if total > 0 {
+                    ui.add(egui::ProgressBar::new((done + failed) as f32 / total as f32)
+                        .text(format!("{} of {} done, {} failed", done, total, failed)));
+                }
+
+                ui.horizontal(|ui| {
+                    let pending = self.items.iter().any(|item| item.status == RowStatus::Pending);
+                    if self.running {
+                        if ui.button("Pause").clicked() {
+                            self.running = false;
+                        }
+                    } else if ui.add_enabled(pending, egui::Button::new(if done + failed > 0 { "Resume" } else { "Start" })).clicked() {
+                        self.running = true;
+                    }
+                    if ui.add_enabled(!self.jobs.is_empty(), egui::Button::new("Stop")).clicked() {
+                        self.running = false;
+                        action = Some(BatchAction::Stop(self.jobs.keys().copied().collect()));
+                    }
+                    if ui.add_enabled(failed > 0, egui::Button::new("Retry failed")).clicked() {
+                        for item in self.items.iter_mut().filter(|item| item.voice.is_some()) {
+                            if matches!(item.status, RowStatus::Failed(_)) {
+                                item.status = RowStatus::Pending;
+                            }
+                        }
+                        self.assign_file_names(template);
+                    }
+                });
+                if let Some(path) = &self.manifest_path {
+                    ui.small(format!("Manifest: {}", path.display()));
+                }
+
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    egui::Grid::new("batch_rows").num_columns(4).striped(true).show(ui, |ui| {
+                        for item in &self.items {
+                            ui.label(&item.row.id);
+                            ui.label(item.voice.as_ref().map_or(item.row.voice.as_str(), |voice| voice.get_voice_name()));
+                            match &item.status {
+                                RowStatus::Failed(error) => {
+                                    ui.colored_label(ui.visuals().error_fg_color, item.status.get_name()).on_hover_text(error);
+                                }
+                                RowStatus::Done(path) => {
+                                    ui.label(item.status.get_name()).on_hover_text(path);
+                                }
+                                status => {
+                                    ui.label(status.get_name());
+                                }
+                            }
+                            let text: String = item.row.text.chars().take(60).collect();
+                            ui.label(text).on_hover_text(&item.row.text);
+                            ui.end_row();
+                        }
+                    });
+
+                    if self.items.is_empty() {
+                        ui.label("No script loaded.");
+                    }
+                });
+            });
+
+        action
+    }
+}
+
+/// Reads a script, JSONL for `.jsonl` and `.ndjson` files and CSV otherwise.
+pub fn read_rows(path: &Path) -> Result<Vec<BatchRow>, PleaseSpeakError> {
+    let content = fs::read_to_string(path).map_err(|e| PleaseSpeakError::Filesystem(format!("{}: {}", path.display(), e)))?;
+    let content = content.trim_start_matches('\u{feff}');
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("jsonl" | "ndjson") => parse_jsonl(content),
+        _ => parse_csv(content),
+    }.map_err(PleaseSpeakError::Other)
+}
+
+fn parse_jsonl(content: &str) -> Result<Vec<BatchRow>, String> {
+    let mut rows = Vec::new();
+    for (number, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let value: Value = serde_json::from_str(line).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        let field = |name: &str| match value.get(name) {
+            Some(Value::String(text)) => text.clone(),
+            Some(Value::Null) | None => String::new(),
+            Some(other) => other.to_string(),
+        };
+
+        let text = field("text");
+        if text.trim().is_empty() {
+            return Err(format!("Line {} has no text", number + 1));
+        }
+        let id = field("id");
+        rows.push(BatchRow {
+            id: if id.is_empty() { (rows.len() + 1).to_string() } else { id },
+            voice: field("voice"),
+            text,
+        });
+    }
+    Ok(rows)
+}
+
+/// Reads CSV with quoted fields, separated by commas, semicolons or tabs as the first line
+/// uses most. Columns are found by their header, or taken as id, voice and text without one.
+fn parse_csv(content: &str) -> Result<Vec<BatchRow>, String> {
+    let first_line = content.lines().next().unwrap_or_default();
+    let separator = [',', ';', '\t'].into_iter()
+        .max_by_key(|separator| first_line.matches(*separator).count())
+        .unwrap_or(',');
+    let mut records = split_records(content, separator);
+
+    let header: Vec<String> = records.first().map(|record| record.iter().map(|field| field.trim().to_lowercase()).collect()).unwrap_or_default();
+    let column = |name: &str| header.iter().position(|field| field == name);
+    let (id, voice, text) = match column("text") {
+        Some(text) => {
+            records.remove(0);
+            (column("id"), column("voice"), text)
+        }
+        None => (Some(0), Some(1), 2),
+    };
+
+    records.into_iter()
+        .enumerate()
+        .map(|(number, record)| {
+            let field = |index: Option<usize>| index.and_then(|index| record.get(index)).map(|field| field.trim().to_string()).unwrap_or_default();
+            if record.len() <= text {
+                return Err(format!("Row {} has no text column", number + 1));
+            }
+            let row_text = field(Some(text));
+            if row_text.is_empty() {
+                return Err(format!("Row {} has no text", number + 1));
+            }
+            let row_id = field(id);
+            Ok(BatchRow {
+                id: if row_id.is_empty() { (number + 1).to_string() } else { row_id },
+                voice: field(voice),
+                text: row_text,
+            })
+        })
+        .collect()
+}
+
+fn split_records(content: &str, separator: char) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+        } else {
+            match c {
+                '"' => quoted = true,
+                '\r' => {}
+                '\n' => {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                c if c == separator => record.push(std::mem::take(&mut field)),
+                c => field.push(c),
+            }
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records.retain(|record| record.iter().any(|field| !field.trim().is_empty()));
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: &str, voice: &str, text: &str) -> BatchRow {
+        BatchRow {
+            id: id.to_string(),
+            voice: voice.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn quoted_fields_keep_separators_quotes_and_newlines() {
+        let records = split_records("a,\"b, c\",\"say \"\"hi\"\"\"\n\"two\nlines\",x,y\n", ',');
+        assert_eq!(records, vec![
+            vec!["a", "b, c", "say \"hi\""],
+            vec!["two\nlines", "x", "y"],
+        ]);
+    }
+
+    #[test]
+    fn crlf_line_endings_are_dropped() {
+        let records = split_records("a,b\r\nc,d\r\n\r\n", ',');
+        assert_eq!(records, vec![vec!["a", "b"], vec!["c", "d"]]);
+    }
+
+    #[test]
+    fn csv_without_header_is_id_voice_text() {
+        let rows = parse_csv("intro,Clyde,\"Hello, world.\"\n,,\"She said \"\"hi\"\".\"\r\n").unwrap();
+        assert_eq!(rows, vec![
+            row("intro", "Clyde", "Hello, world."),
+            row("2", "", "She said \"hi\"."),
+        ]);
+    }
+
+    #[test]
+    fn csv_header_finds_columns_in_any_order() {
+        let rows = parse_csv("Text,Voice,ID\r\nHello,Clyde,a\r\n\"Bye, now\",,b\r\n").unwrap();
+        assert_eq!(rows, vec![row("a", "Clyde", "Hello"), row("b", "", "Bye, now")]);
+
+        let rows = parse_csv("voice,text\nClyde,Hello\n").unwrap();
+        assert_eq!(rows, vec![row("1", "Clyde", "Hello")]);
+    }
+
+    #[test]
+    fn semicolons_and_tabs_are_detected() {
+        let rows = parse_csv("id;voice;text\n1;Clyde;\"Hello, world; again\"\n").unwrap();
+        assert_eq!(rows, vec![row("1", "Clyde", "Hello, world; again")]);
+
+        let rows = parse_csv("id\tvoice\ttext\n1\tClyde\tHello, world\n").unwrap();
+        assert_eq!(rows, vec![row("1", "Clyde", "Hello, world")]);
+    }
+
+    #[test]
+    fn csv_rows_without_text_are_refused() {
+        let error = parse_csv("id,voice,text\n1,Clyde,Hello\n2,Clyde\n").unwrap_err();
+        assert_eq!(error, "Row 2 has no text column");
+    }
+
+    #[test]
+    fn rows_with_empty_text_are_refused() {
+        assert_eq!(parse_csv("id,voice,text\n1,Clyde,Hello\n2,Clyde,  \n").unwrap_err(), "Row 2 has no text");
+        assert_eq!(parse_jsonl("{\"text\": \"Hello\"}\n\n{\"text\": \"\"}\n").unwrap_err(), "Line 3 has no text");
+    }
+
+    #[test]
+    fn file_names_are_sanitized_and_numbered() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut batch = Batch::new(runtime.handle().clone());
+        batch.items = [row("../up", "Clyde", "Hello"), row("../up", "Clyde", "Bye")].into_iter()
+            .map(|row| BatchItem { row, voice: None, file_name: String::new(), status: RowStatus::Pending })
+            .collect();
+
+        batch.assign_file_names("../{voice}/{id}");
+        let names: Vec<&str> = batch.items.iter().map(|item| item.file_name.as_str()).collect();
+        assert_eq!(names, ["../Clyde/_up", "../Clyde/_up_2"]);
+
+        let dir = std::env::temp_dir().join(format!("please_speak_batch_names_{}", std::process::id()));
+        assert_eq!(output_path(&dir, names[0], "mp3", Collision::Overwrite), dir.join("Clyde").join("_up.mp3"));
+    }
+
+    #[test]
+    fn jsonl_reads_each_line() {
+        let rows = parse_jsonl("{\"id\": \"a\", \"voice\": \"Clyde\", \"text\": \"Hello\"}\r\n\n{\"text\": \"Bye\", \"id\": 7, \"voice\": null}\n{\"text\": \"Again\"}\n").unwrap();
+        assert_eq!(rows, vec![
+            row("a", "Clyde", "Hello"),
+            row("7", "", "Bye"),
+            row("3", "", "Again"),
+        ]);
+    }
+
+    #[test]
+    fn jsonl_reports_the_bad_line() {
+        let error = parse_jsonl("{\"text\": \"Hello\"}\n\n{\"text\": \"Bye\"\n").unwrap_err();
+        assert!(error.starts_with("Line 3: "), "{}", error);
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use async_channel::Sender;
+use elevenlabs_rs::Bytes;
+use sha2::{Digest, Sha256};
+use crate::app::APP_NAME;
+use crate::errors::PleaseSpeakError;
+use crate::provider::{SpeechProvider, SpeechRequest};
+
+pub const DEFAULT_CACHE_SIZE_MB: u64 = 500;
+
+/// Generated audio stored on disk under the hash of everything that produced it.
+///
+/// Entries are evicted least recently used first once the directory grows over `max_bytes`.
+#[derive(Clone)]
+pub struct SpeechCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl SpeechCache {
+    pub fn new(max_size_mb: u64) -> Self {
+        Self {
+            dir: eframe::storage_dir(APP_NAME).unwrap_or_else(std::env::temp_dir).join("cache"),
+            max_bytes: max_size_mb * 1024 * 1024,
+        }
+    }
+
+    /// Names the entry of `request`, from what changes the audio only: renaming a voice keeps
+    /// its entries.
+    pub fn key(provider: &dyn SpeechProvider, request: &SpeechRequest) -> String {
+        key_of(provider.name(), provider.output_format(), request)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let path = self.dir.join(key);
+        let bytes = fs::read(&path).ok()?;
+
+        // The modification time doubles as the last access time for eviction.
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+
+        Some(Bytes::from(bytes))
+    }
+
+    pub fn put(&self, key: &str, bytes: &Bytes) -> Result<(), PleaseSpeakError> {
+        fs::create_dir_all(&self.dir).map_err(|e| PleaseSpeakError::Filesystem(format!("Cache Error: {}", e)))?;
+        fs::write(self.dir.join(key), bytes).map_err(|e| PleaseSpeakError::Filesystem(format!("Cache Error: {}", e)))?;
+        self.evict()
+    }
+
+    /// Returns the number of entries and their total size in bytes.
+    pub fn usage(&self) -> (usize, u64) {
+        let entries = self.entries();
+        (entries.len(), entries.iter().map(|(_, size, _)| size).sum())
+    }
+
+    pub fn clear(&self) -> Result<(), PleaseSpeakError> {
+        for (path, _, _) in self.entries() {
+            fs::remove_file(path).map_err(|e| PleaseSpeakError::Filesystem(format!("Cache Error: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<(PathBuf, u64, SystemTime)> {
+        let Ok(read_dir) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+
+        read_dir
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+            })
+            .collect()
+    }
+
+    fn evict(&self) -> Result<(), PleaseSpeakError> {
+        let mut entries = self.entries();
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(_, _, modified)| *modified);
+
+        for (path, size, _) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(&path).map_err(|e| PleaseSpeakError::Filesystem(format!("Cache Error: {}", e)))?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    /// Returns the cached audio for `request`, or generates and caches it.
+    ///
+    /// `force` skips the lookup but still refreshes the cached copy. The flag in the result
+    /// tells whether the audio came from the cache. With `chunks` the audio is streamed there
+    /// as well, a cached copy being sent as a single chunk.
+    pub async fn generate(&self, provider: &dyn SpeechProvider, request: SpeechRequest, force: bool, chunks: Option<Sender<Bytes>>) -> Result<(Bytes, bool), PleaseSpeakError> {
+        let key = Self::key(provider, &request);
+        if !force {
+            if let Some(bytes) = self.get(&key) {
+                if let Some(chunks) = chunks {
+                    let _ = chunks.send(bytes.clone()).await;
+                }
+                return Ok((bytes, true));
+            }
+        }
+
+        let bytes = match chunks {
+            Some(chunks) => provider.generate_stream(request, chunks).await?,
+            None => provider.generate_speak(request).await?,
+        };
+        if let Err(error) = self.put(&key, &bytes) {
+            log::warn!("{}", error);
+        }
+        Ok((bytes, false))
+    }
+}
+
+fn key_of(provider: &str, output_format: &str, request: &SpeechRequest) -> String {
+    let mut hasher = Sha256::new();
+    // Each value is prefixed by its length, so moving text from a field to the next changes the key.
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+
+    field(provider.as_bytes());
+    field(output_format.as_bytes());
+    field(request.voice.get_voice_id().as_bytes());
+    field(request.model.as_bytes());
+    match &request.settings {
+        Some(settings) => {
+            field(&settings.stability.to_le_bytes());
+            field(&settings.similarity_boost.to_le_bytes());
+            field(&settings.style.to_le_bytes());
+            field(&[settings.use_speaker_boost as u8]);
+            field(&settings.speed.to_le_bytes());
+        }
+        None => field(&[]),
+    }
+    field(request.text.as_bytes());
+    field(request.previous_text.as_deref().unwrap_or_default().as_bytes());
+    field(request.next_text.as_deref().unwrap_or_default().as_bytes());
+
+    hasher.finalize().iter().fold(String::new(), |mut key, byte| {
+        let _ = write!(key, "{:02x}", byte);
+        key
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Voice, VoiceSettings};
+
+    fn request() -> SpeechRequest {
+        SpeechRequest {
+            text: "Hello".to_string(),
+            voice: Voice::new("voice_id".to_string(), "Clyde".to_string()),
+            model: "eleven_turbo_v2_5".to_string(),
+            settings: None,
+            previous_text: None,
+            next_text: None,
+        }
+    }
+
+    fn key(request: &SpeechRequest) -> String {
+        key_of("ElevenLabs", "mp3_44100_128", request)
+    }
+
+    #[test]
+    fn renaming_the_voice_keeps_the_key() {
+        let renamed = SpeechRequest { voice: Voice::new("voice_id".to_string(), "Clyde (old)".to_string()), ..request() };
+        assert_eq!(key(&renamed), key(&request()));
+    }
+
+    #[test]
+    fn what_changes_the_audio_changes_the_key() {
+        let base = key(&request());
+        let changed = [
+            SpeechRequest { voice: Voice::new("other_id".to_string(), "Clyde".to_string()), ..request() },
+            SpeechRequest { model: "eleven_multilingual_v2".to_string(), ..request() },
+            SpeechRequest { settings: Some(VoiceSettings::default()), ..request() },
+            SpeechRequest { settings: Some(VoiceSettings { speed: 1.1, ..VoiceSettings::default() }), ..request() },
+            SpeechRequest { text: "Hello!".to_string(), ..request() },
+            SpeechRequest { previous_text: Some("Before".to_string()), ..request() },
+            SpeechRequest { next_text: Some("After".to_string()), ..request() },
+        ];
+        for request in &changed {
+            assert_ne!(key(request), base, "{:?}", request);
+        }
+        assert_ne!(key(&changed[2]), key(&changed[3]));
+        assert_ne!(key_of("ElevenLabs", "pcm_44100", &request()), base);
+        assert_ne!(key_of("Other", "mp3_44100_128", &request()), base);
+    }
+
+    #[test]
+    fn text_cannot_move_between_fields() {
+        let first = SpeechRequest { text: "Hel".to_string(), previous_text: Some("lo".to_string()), ..request() };
+        let second = SpeechRequest { text: "Hello".to_string(), previous_text: Some(String::new()), ..request() };
+        assert_ne!(key(&first), key(&second));
+    }
+}
@@ -0,0 +1,311 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use elevenlabs_rs::Bytes;
+use crate::app::Configuration;
+use crate::audio::ExportFormat;
+use crate::cache::SpeechCache;
+use crate::device::{resolve_routes, PSDevice};
+use crate::naming::{output_path, render_template};
+use crate::playback::play_blocking;
+use crate::provider::{run_sync, SpeechProvider, SpeechRequest};
+use crate::retry::RetryPolicy;
+use crate::segment::{generate_segments, split_text};
+use crate::{Model, Voice};
+
+const USAGE: &str = "Usage: please_speak [COMMAND] [OPTIONS]
+
+Without a command the graphical interface is started.
+
+Commands:
+  voices              List the voices available for the API key
+  models              List the models available for the API key
+  say <TEXT>          Generate the text and play it on the output device
+  render <TEXT>       Generate the text and write the audio to a file
+
+Options:
+  --api-key <KEY>     API key to use instead of the saved one
+  --voice <VOICE>     Voice id or name to use instead of the saved one
+  --model <MODEL>     Model id or name to use instead of the saved one
+  --save-to <DIR>     Directory used when --out is not given
+  --out <FILE>        File to write the audio to, `-` for stdout (render only),
+                      required when the file name template uses {counter}
+  --format <FORMAT>   original, wav, flac or ogg instead of the saved export format
+  --no-cache          Always call the API instead of reusing an identical generation
+  -h, --help          Print this help
+
+TEXT can be omitted or set to `-` to read it from stdin.
+When the API key is kept in an encrypted file, its passphrase is read from PLEASE_SPEAK_PASSPHRASE.";
+
+const PASSPHRASE_VAR: &str = "PLEASE_SPEAK_PASSPHRASE";
+
+enum Command {
+    Voices,
+    Models,
+    Say,
+    Render,
+}
+
+struct Args {
+    command: Command,
+    text: Option<String>,
+    api_key: Option<String>,
+    voice: Option<String>,
+    model: Option<String>,
+    save_to: Option<String>,
+    out: Option<String>,
+    format: Option<String>,
+    no_cache: bool,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut iter = args.iter();
+        let command = match iter.next().map(String::as_str) {
+            Some("voices") => Command::Voices,
+            Some("models") => Command::Models,
+            Some("say") => Command::Say,
+            Some("render") => Command::Render,
+            Some(other) => return Err(format!("Unknown command: {}", other)),
+            None => return Err("Missing command".to_string()),
+        };
+
+        let mut parsed = Self {
+            command,
+            text: None,
+            api_key: None,
+            voice: None,
+            model: None,
+            save_to: None,
+            out: None,
+            format: None,
+            no_cache: false,
+        };
+
+        while let Some(arg) = iter.next() {
+            let slot = match arg.as_str() {
+                "--api-key" => &mut parsed.api_key,
+                "--voice" => &mut parsed.voice,
+                "--model" => &mut parsed.model,
+                "--save-to" => &mut parsed.save_to,
+                "--out" => &mut parsed.out,
+                "--format" => &mut parsed.format,
+                "--no-cache" => {
+                    parsed.no_cache = true;
+                    continue;
+                }
+                flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+                _ => {
+                    if parsed.text.is_some() {
+                        return Err(format!("Unexpected argument: {}", arg));
+                    }
+                    parsed.text = Some(arg.clone());
+                    continue;
+                }
+            };
+
+            match iter.next() {
+                Some(value) => *slot = Some(value.clone()),
+                None => return Err(format!("Missing value for {}", arg)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn read_text(&self) -> Result<String, String> {
+        match self.text.as_deref() {
+            Some(text) if text != "-" => Ok(text.to_string()),
+            _ => {
+                let mut text = String::new();
+                std::io::stdin().read_to_string(&mut text).map_err(|e| format!("Could not read stdin: {}", e))?;
+                Ok(text.trim_end().to_string())
+            }
+        }
+    }
+}
+
+/// Runs a headless command and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return 0;
+    }
+
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("Error: {}\n\n{}", error, USAGE);
+            return 2;
+        }
+    };
+
+    match execute(args) {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            1
+        }
+    }
+}
+
+fn execute(args: Args) -> Result<(), String> {
+    let mut configuration = Configuration::load().unwrap_or_default();
+    if let Some(api_key) = &args.api_key {
+        configuration.api_key = api_key.clone();
+    }
+    if let Some(save_to) = &args.save_to {
+        configuration.save_to = save_to.clone();
+    }
+    if let Some(format) = &args.format {
+        configuration.export_format = ExportFormat::parse(format).ok_or_else(|| format!("Unknown format: {}", format))?;
+    }
+    if configuration.api_key.is_empty() {
+        let passphrase = std::env::var(PASSPHRASE_VAR).unwrap_or_default();
+        configuration.api_key = configuration.key_storage.load(&passphrase).map_err(|e| e.to_string())?.unwrap_or_default();
+    }
+    if configuration.api_key.is_empty() {
+        return Err("No API key, set one in the app or pass --api-key".to_string());
+    }
+
+    let provider = run_sync(configuration.provider.create(configuration.api_key.clone(), RetryPolicy::new(configuration.max_attempts)))
+        .map_err(|reason| format!("Could not connect: {}", reason))?;
+
+    match args.command {
+        Command::Voices => {
+            let voices = run_sync(provider.get_voices()).map_err(|e| format!("Could not load voices: {}", e))?;
+            for voice in voices {
+                println!("{}\t{}", voice.get_voice_id(), voice.get_voice_name());
+            }
+            Ok(())
+        }
+        Command::Models => {
+            let models = run_sync(provider.get_models()).map_err(|e| format!("Could not load models: {}", e))?;
+            for model in models {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    model.get_model_id(),
+                    model.get_model_name(),
+                    model.get_max_characters(),
+                    model.get_languages().join(", ")
+                );
+            }
+            Ok(())
+        }
+        Command::Say => {
+            let voice = resolve_voice(&provider, &args, &configuration)?;
+            let model = resolve_model(&provider, &args, &configuration)?;
+            let (_, bytes) = generate(&provider, &args, &configuration, voice, model)?;
+            play(&configuration, bytes)
+        }
+        Command::Render => {
+            let voice = resolve_voice(&provider, &args, &configuration)?;
+            let model = resolve_model(&provider, &args, &configuration)?;
+            let (request, bytes) = generate(&provider, &args, &configuration, voice, model)?;
+            let extension = configuration.export_format.extension(&bytes);
+            let bytes = configuration.export_format.export(&bytes)?;
+            match args.out.as_deref() {
+                Some("-") => std::io::stdout().write_all(&bytes).map_err(|e| format!("Could not write to stdout: {}", e)),
+                Some(out) => write_file(Path::new(out), &bytes),
+                None => {
+                    // The counter belongs to the app, which would overwrite it on exit if it was saved from here.
+                    if configuration.file_name_template.contains("{counter}") {
+                        return Err("The file name template uses {counter}, which only the graphical interface fills. Use --out or change the template".to_string());
+                    }
+                    let name = render_template(&configuration.file_name_template, &request, configuration.save_counter);
+                    let path = output_path(Path::new(&configuration.save_to), &name, extension, configuration.collision);
+                    write_file(&path, &bytes)
+                }
+            }
+        }
+    }
+}
+
+fn resolve_voice(
+    provider: &Arc<dyn SpeechProvider>,
+    args: &Args,
+    configuration: &Configuration,
+) -> Result<Voice, String> {
+    let Some(wanted) = &args.voice else {
+        return Ok(configuration.voice.clone());
+    };
+
+    let voices = run_sync(provider.get_voices()).map_err(|e| format!("Could not load voices: {}", e))?;
+    voices
+        .into_iter()
+        .find(|voice| voice.get_voice_id() == wanted || voice.get_voice_name().eq_ignore_ascii_case(wanted))
+        .ok_or_else(|| format!("Unknown voice: {}", wanted))
+}
+
+fn resolve_model(
+    provider: &Arc<dyn SpeechProvider>,
+    args: &Args,
+    configuration: &Configuration,
+) -> Result<Model, String> {
+    let Some(wanted) = &args.model else {
+        return Ok(configuration.model.clone());
+    };
+
+    let models = run_sync(provider.get_models()).map_err(|e| format!("Could not load models: {}", e))?;
+    models
+        .into_iter()
+        .find(|model| model.get_model_id() == wanted || model.get_model_name().eq_ignore_ascii_case(wanted))
+        .ok_or_else(|| format!("Unknown model: {}", wanted))
+}
+
+fn generate(
+    provider: &Arc<dyn SpeechProvider>,
+    args: &Args,
+    configuration: &Configuration,
+    voice: Voice,
+    model: Model,
+) -> Result<(SpeechRequest, Bytes), String> {
+    let text = args.read_text()?;
+    if text.is_empty() {
+        return Err("Nothing to say".to_string());
+    }
+
+    let request = SpeechRequest {
+        text,
+        settings: configuration.get_voice_settings(&voice),
+        voice,
+        model: model.get_model_id().to_string(),
+        previous_text: None,
+        next_text: None,
+    };
+    let cache = (configuration.cache_enabled && !args.no_cache).then(|| SpeechCache::new(configuration.cache_size_mb));
+
+    let segments = split_text(&request.text, model.get_max_characters());
+    if segments.len() > 1 {
+        let progress = |done, total| eprintln!("Generated segment {} of {}", done, total);
+        let result = run_sync(generate_segments(provider.as_ref(), cache.as_ref(), &request, &segments, false, progress));
+        return result.map(|(bytes, _)| (request, bytes)).map_err(|error| format!("Generation failed: {}", error));
+    }
+
+    let bytes = match cache {
+        Some(cache) => run_sync(cache.generate(provider.as_ref(), request.clone(), false, None)).map(|(bytes, _)| bytes),
+        None => run_sync(provider.generate_speak(request.clone())),
+    };
+
+    bytes.map(|bytes| (request, bytes)).map_err(|error| format!("Generation failed: {}", error))
+}
+
+fn play(configuration: &Configuration, bytes: Bytes) -> Result<(), String> {
+    // Like the app, fall back to the default output while a routed device is unplugged.
+    let devices = PSDevice::list().map_err(|e| e.to_string())?;
+    let routes = resolve_routes(&configuration.output_routes, &devices);
+    if routes.is_empty() {
+        return Err("No output device is enabled".to_string());
+    }
+    play_blocking(&bytes, &routes).map_err(|e| e.to_string())
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Could not create {}: {}", parent.display(), e))?;
+    }
+    fs::write(path, bytes).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    eprintln!("Saved to: {}", path.display());
+    Ok(())
+}
@@ -1,31 +1,114 @@
-use rodio::{Device, DeviceTrait};
+use rodio::{cpal, Device, DeviceTrait};
 use rodio::cpal::traits::HostTrait;
 use serde::{Deserialize, Serialize};
+use crate::errors::PleaseSpeakError;
 use crate::TtsApp;
 
-#[derive(Clone, Serialize, Deserialize)]
+/// An output device, saved by name. An empty name stands for the default output.
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct PSDevice {
     pub device_name: String,
+    /// Tells apart devices with the same name, like two identical USB interfaces, in the order
+    /// the host lists them.
+    #[serde(default)]
+    pub index: usize,
 }
 
 impl PSDevice {
-    pub fn new(device: Device) -> Self {
-        Self {
-            device_name: device.name().unwrap(),
+    /// `None` when the device does not tell its name, it could not be found again.
+    pub fn new(device: Device) -> Option<Self> {
+        Some(Self {
+            device_name: device.name().ok()?,
+            index: 0,
+        })
+    }
+
+    /// Every output device the host lists now.
+    pub fn list() -> Result<Vec<PSDevice>, PleaseSpeakError> {
+        let mut devices: Vec<PSDevice> = Vec::new();
+        for device in TtsApp::get_devices()?.into_iter().filter_map(PSDevice::new) {
+            let index = devices.iter().filter(|other| other.device_name == device.device_name).count();
+            devices.push(PSDevice { index, ..device });
         }
+        Ok(devices)
     }
 
-    pub fn get_device_name(&self) -> &str {
-        &self.device_name
+    pub fn is_default(&self) -> bool {
+        self.device_name.is_empty()
+    }
+
+    pub fn get_device_name(&self) -> String {
+        match (self.is_default(), self.index) {
+            (true, _) => "Default output".to_string(),
+            (false, 0) => self.device_name.clone(),
+            (false, index) => format!("{} ({})", self.device_name, index + 1),
+        }
     }
 
-    pub fn get_device(&self) -> Device {
-        TtsApp::get_devices().iter().find(|d| d.name().unwrap() == self.device_name).unwrap().clone()
+    pub fn get_device(&self) -> Result<Device, PleaseSpeakError> {
+        if self.is_default() {
+            return cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| PleaseSpeakError::AudioDevice("No output device was found".to_string()));
+        }
+
+        TtsApp::get_devices()?
+            .into_iter()
+            .filter(|device| device.name().is_ok_and(|name| name == self.device_name))
+            .nth(self.index)
+            .ok_or_else(|| PleaseSpeakError::AudioDevice(format!("The output device \"{}\" is not available", self.get_device_name())))
     }
 }
 
 impl PartialEq for PSDevice {
     fn eq(&self, other: &Self) -> bool {
-        self.device_name == other.device_name
+        self.device_name == other.device_name && self.index == other.index
+    }
+}
+
+/// One of the devices the speech is played on, like a virtual cable for the stream and
+/// headphones to monitor it.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputRoute {
+    pub device: PSDevice,
+    /// Applied on top of the player volume.
+    pub volume: f32,
+    pub enabled: bool,
+}
+
+impl Default for OutputRoute {
+    fn default() -> Self {
+        Self {
+            device: PSDevice::default(),
+            volume: 1.0,
+            enabled: true,
+        }
+    }
+}
+
+impl OutputRoute {
+    pub fn new(device: PSDevice) -> Self {
+        Self {
+            device,
+            ..Self::default()
+        }
+    }
+}
+
+/// The enabled routes, with the default output standing in for devices missing from
+/// `available`. A device is only played on once.
+pub fn resolve_routes(routes: &[OutputRoute], available: &[PSDevice]) -> Vec<OutputRoute> {
+    let mut resolved: Vec<OutputRoute> = Vec::new();
+    for route in routes.iter().filter(|route| route.enabled) {
+        let device = if route.device.is_default() || available.contains(&route.device) {
+            route.device.clone()
+        } else {
+            PSDevice::default()
+        };
+        if !resolved.iter().any(|other| other.device == device) {
+            resolved.push(OutputRoute { device, ..route.clone() });
+        }
     }
+    resolved
 }
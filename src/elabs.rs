@@ -1,15 +1,31 @@
-use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use async_channel::Sender;
-use elevenlabs_rs::{Bytes, ElevenLabsClient, Model, TextToSpeech, TextToSpeechBody};
+use eframe::egui;
+use elevenlabs_rs::endpoints::{Endpoint, Method, RequestBody, Response, Url, BASE_URL};
+use elevenlabs_rs::Bytes;
+use futures::future::BoxFuture;
+use reqwest::header::RETRY_AFTER;
 use serde::{Deserialize, Serialize};
-use tokio::runtime::Runtime;
+use crate::errors::PleaseSpeakError;
+use crate::provider::{Account, Capabilities, SpeechProvider, SpeechRequest};
+use crate::retry::RetryPolicy;
+
+pub const DEFAULT_MODEL_ID: &str = "eleven_multilingual_v2";
+/// What the text-to-speech endpoint returns when no output format is asked for.
+pub const DEFAULT_OUTPUT_FORMAT: &str = "mp3_44100_128";
+/// How long the connection check may take before giving up, so being offline does not hang.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const XI_API_KEY_HEADER: &str = "xi-api-key";
 
 #[derive(Clone)]
 pub struct Elabs {
-    eleven_labs_client: Option<ElevenLabsClient>,
+    http: reqwest::Client,
+    api_key: Option<String>,
     connected: bool,
-    api_error_tx: Sender<String>,
-    elabs_error_tx: Sender<String>,
+    retry: RetryPolicy,
+    /// The voices listed by the connection check, handed out by the next `get_voices`.
+    init_voices: Arc<Mutex<Option<Vec<Voice>>>>,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
@@ -28,6 +44,10 @@ impl Default for Voice {
 }
 
 impl Voice {
+    pub fn new(voice_id: String, voice_name: String) -> Self {
+        Self { voice_id, voice_name }
+    }
+
     pub fn get_voice_id(&self) -> &str {
         &self.voice_id
     }
@@ -37,92 +57,464 @@ impl Voice {
     }
 }
 
-impl Elabs {
-    pub fn new(api_error_tx: Sender<String>, elabs_error_tx: Sender<String>) -> Self {
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct Model {
+    model_id: String,
+    model_name: String,
+    languages: Vec<String>,
+    max_characters: usize,
+}
+
+impl Default for Model {
+    fn default() -> Self {
         Self {
-            eleven_labs_client: None,
-            connected: false,
-            api_error_tx,
-            elabs_error_tx,
+            model_id: DEFAULT_MODEL_ID.to_string(),
+            model_name: "Eleven Multilingual v2".to_string(),
+            languages: Vec::new(),
+            max_characters: 10_000,
         }
     }
+}
+
+impl Model {
+    pub fn get_model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    pub fn get_model_name(&self) -> &str {
+        &self.model_name
+    }
+
+    pub fn get_languages(&self) -> &[String] {
+        &self.languages
+    }
 
-    pub fn run_sync<'a, F, Fut>(&'a self, method: F) -> Fut::Output
-    where
-        F: FnOnce(&'a Elabs) -> Fut,
-        Fut: Future,
-    {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(method(self))
+    /// Longest text accepted in a single request.
+    pub fn get_max_characters(&self) -> usize {
+        self.max_characters
     }
+}
+
+/// How a voice is rendered, sent along with each request when the user tuned it.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct VoiceSettings {
+    pub stability: f32,
+    pub similarity_boost: f32,
+    pub style: f32,
+    pub use_speaker_boost: bool,
+    pub speed: f32,
+}
 
-    pub fn init(&mut self, api_key: String) {
-        self.eleven_labs_client = Some(ElevenLabsClient::new(api_key));
-        if self.run_sync(|elabs| {
-            elabs.get_voices(false)
-        }).is_some() {
-            self.connected = true;
-            return;
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            stability: 0.5,
+            similarity_boost: 0.75,
+            style: 0.0,
+            use_speaker_boost: true,
+            speed: 1.0,
         }
+    }
+}
+
+impl VoiceSettings {
+    /// Draws a slider for each setting and returns whether one changed.
+    pub fn ui(&mut self, ui: &mut egui::Ui, id_source: impl std::hash::Hash) -> bool {
+        let mut changed = false;
+        egui::Grid::new(id_source).num_columns(2).show(ui, |ui| {
+            ui.label("Stability:");
+            changed |= ui.add(egui::Slider::new(&mut self.stability, 0.0..=1.0)).changed();
+            ui.end_row();
 
-        self.run_sync(|elabs| {
-            elabs.capture_error("ElevenLabsClient not initialized, please set your API key on the settings page")
+            ui.label("Similarity:");
+            changed |= ui.add(egui::Slider::new(&mut self.similarity_boost, 0.0..=1.0)).changed();
+            ui.end_row();
+
+            ui.label("Style exaggeration:");
+            changed |= ui.add(egui::Slider::new(&mut self.style, 0.0..=1.0)).changed();
+            ui.end_row();
+
+            ui.label("Speed:");
+            changed |= ui.add(egui::Slider::new(&mut self.speed, 0.7..=1.2)).changed();
+            ui.end_row();
+
+            ui.label("Speaker boost:");
+            changed |= ui.checkbox(&mut self.use_speaker_boost, "").changed();
+            ui.end_row();
         });
+        changed
+    }
+}
 
-        self.connected = false;
+/// `POST /v1/text-to-speech/{voice_id}`, with a body of our own because
+/// [`elevenlabs_rs::TextToSpeechBody`] has no way to send the speed.
+#[derive(Clone)]
+struct Speak {
+    voice_id: String,
+    body: SpeakBody,
+}
+
+#[derive(Serialize, Clone)]
+struct SpeakBody {
+    text: String,
+    model_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    voice_settings: Option<VoiceSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_text: Option<String>,
+}
+
+impl From<SpeechRequest> for SpeakBody {
+    fn from(request: SpeechRequest) -> Self {
+        Self {
+            text: request.text,
+            model_id: request.model,
+            voice_settings: request.settings,
+            previous_text: request.previous_text,
+            next_text: request.next_text,
+        }
     }
+}
 
-    pub fn connected(&self) -> bool {
-        self.connected
+impl Endpoint for Speak {
+    type ResponseBody = Bytes;
+
+    fn method(&self) -> Method {
+        Method::POST
     }
 
-    pub async fn capture_error(&self, error: &str) {
-        let _ = self.elabs_error_tx.send(error.to_string()).await;
-    }
-
-    pub async fn get_voices(&self, raise: bool) -> Option<Vec<Voice>> {
-        if let Some(client) = &self.eleven_labs_client {
-            match client.hit(elevenlabs_rs::GetVoices).await {
-                Ok(result) => Some(
-                    result
-                        .get_voices()
-                        .iter()
-                        .map(|voice| Voice {
-                            voice_id: voice.get_voice_id().to_string(),
-                            voice_name: voice.get_name().to_string(),
-                        }).collect()
-                ),
-                Err(e) => {
-                    if raise {
-                        let _ = self.api_error_tx.send(format!("API Error: {:?}", e)).await;
-                    }
-                    None
-                }
-            }
-        } else {
-            let _ = self.elabs_error_tx.send("ElevenLabsClient not initialized".to_string()).await;
-            None
+    fn request_body(&self) -> elevenlabs_rs::Result<RequestBody> {
+        Ok(RequestBody::Json(serde_json::to_value(&self.body)?))
+    }
+
+    async fn response_body(self, resp: Response) -> elevenlabs_rs::Result<Self::ResponseBody> {
+        Ok(resp.bytes().await?)
+    }
+
+    fn url(&self) -> Url {
+        let mut url = BASE_URL.parse::<Url>().unwrap();
+        url.set_path(&format!("v1/text-to-speech/{}", self.voice_id));
+        url
+    }
+}
+
+/// `POST /v1/text-to-speech/{voice_id}/stream`, returning the response to read it chunk by chunk.
+#[derive(Clone)]
+struct SpeakStream {
+    voice_id: String,
+    body: SpeakBody,
+}
+
+impl Endpoint for SpeakStream {
+    type ResponseBody = Response;
+
+    fn method(&self) -> Method {
+        Method::POST
+    }
+
+    fn request_body(&self) -> elevenlabs_rs::Result<RequestBody> {
+        Ok(RequestBody::Json(serde_json::to_value(&self.body)?))
+    }
+
+    async fn response_body(self, resp: Response) -> elevenlabs_rs::Result<Self::ResponseBody> {
+        Ok(resp)
+    }
+
+    fn url(&self) -> Url {
+        let mut url = BASE_URL.parse::<Url>().unwrap();
+        url.set_path(&format!("v1/text-to-speech/{}/stream", self.voice_id));
+        url
+    }
+}
+
+/// `GET /v1/voices/{voice_id}/settings`, the settings saved with the voice on the website.
+#[derive(Clone)]
+struct GetSettings {
+    voice_id: String,
+}
+
+impl Endpoint for GetSettings {
+    type ResponseBody = VoiceSettings;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    async fn response_body(self, resp: Response) -> elevenlabs_rs::Result<Self::ResponseBody> {
+        Ok(resp.json().await?)
+    }
+
+    fn url(&self) -> Url {
+        let mut url = BASE_URL.parse::<Url>().unwrap();
+        url.set_path(&format!("v1/voices/{}/settings", self.voice_id));
+        url
+    }
+}
+
+/// `GET /v1/user`, read leniently since [`elevenlabs_rs::UserInfo`] fails on any missing field.
+#[derive(Clone)]
+struct GetUser;
+
+#[derive(Deserialize)]
+struct User {
+    #[serde(default)]
+    first_name: Option<String>,
+    #[serde(default)]
+    subscription: UserSubscription,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct UserSubscription {
+    tier: String,
+    character_count: u64,
+    character_limit: u64,
+    next_character_count_reset_unix: Option<i64>,
+}
+
+impl Endpoint for GetUser {
+    type ResponseBody = User;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    async fn response_body(self, resp: Response) -> elevenlabs_rs::Result<Self::ResponseBody> {
+        Ok(resp.json().await?)
+    }
+
+    fn url(&self) -> Url {
+        let mut url = BASE_URL.parse::<Url>().unwrap();
+        url.set_path("v1/user");
+        url
+    }
+}
+
+/// `GET /v1/models`, read into our own types because the fields of
+/// [`elevenlabs_rs::endpoints::models::Model`] are private.
+#[derive(Clone)]
+struct ListModels;
+
+#[derive(Deserialize)]
+struct ListedModel {
+    model_id: String,
+    name: String,
+    #[serde(default)]
+    can_do_text_to_speech: bool,
+    #[serde(default)]
+    maximum_text_length_per_request: f32,
+    #[serde(default)]
+    languages: Vec<ListedLanguage>,
+}
+
+#[derive(Deserialize)]
+struct ListedLanguage {
+    name: String,
+}
+
+impl Endpoint for ListModels {
+    type ResponseBody = Vec<ListedModel>;
+
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    async fn response_body(self, resp: Response) -> elevenlabs_rs::Result<Self::ResponseBody> {
+        Ok(resp.json().await?)
+    }
+
+    fn url(&self) -> Url {
+        let mut url = BASE_URL.parse::<Url>().unwrap();
+        url.set_path("v1/models");
+        url
+    }
+}
+
+impl Elabs {
+    pub fn new(retry: RetryPolicy) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_key: None,
+            connected: false,
+            retry,
+            init_voices: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub async fn generate_speak(&self, text: String, voice: Voice, raise: bool) -> Option<Bytes> {
-        if let Some(client) = &self.eleven_labs_client {
-            let body = TextToSpeechBody::new(text.as_str(), Model::ElevenMultilingualV2);
-            let endpoint = TextToSpeech::new(voice.get_voice_id(), body);
+    /// Connects with `api_key`, checking it by listing the voices once.
+    pub async fn init(&mut self, api_key: String) -> Result<(), PleaseSpeakError> {
+        self.api_key = Some(api_key);
+        let check = tokio::time::timeout(CONNECT_TIMEOUT, self.send(elevenlabs_rs::GetVoices)).await;
+        self.connected = matches!(check, Ok(Ok(_)));
 
-            match client.hit(endpoint).await {
-                Ok(bytes) => Some(bytes),
-                Err(e) => {
-                    if raise {
-                        let _ = self.api_error_tx.send(format!("API Error: {:?}", e)).await;
-                    }
-                    None
-                }
+        match check {
+            Ok(Ok(result)) => {
+                *self.init_voices.lock().unwrap() = Some(voices_of(&result));
+                Ok(())
             }
-        } else {
-            let _ = self.elabs_error_tx.send("ElevenLabsClient not initialized".to_string()).await;
-            None
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(PleaseSpeakError::Network(format!("No answer from ElevenLabs after {} seconds", CONNECT_TIMEOUT.as_secs()))),
+        }
+    }
+
+    /// Sends `endpoint`, trying again as the retry policy allows.
+    async fn hit<T: Endpoint + Clone>(&self, endpoint: T) -> Result<T::ResponseBody, PleaseSpeakError> {
+        self.retry.run(|| self.send(endpoint.clone())).await
+    }
+
+    /// Sends `endpoint` like `ElevenLabsClient::hit` does, but keeps the status and headers of
+    /// error answers so they can be told apart.
+    async fn send<T: Endpoint>(&self, endpoint: T) -> Result<T::ResponseBody, PleaseSpeakError> {
+        let Some(api_key) = &self.api_key else {
+            return Err(PleaseSpeakError::Auth("No API key was given".to_string()));
+        };
+
+        let request = self.http.request(endpoint.method(), endpoint.url()).header(XI_API_KEY_HEADER, api_key);
+        let request = match endpoint.request_body().map_err(boxed_error)? {
+            RequestBody::Json(json) => request.json(&json),
+            RequestBody::Multipart(form) => request.multipart(form),
+            RequestBody::Empty => request,
+        };
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response.headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(Duration::from_secs);
+            let body = response.text().await.unwrap_or_default();
+            return Err(PleaseSpeakError::from_status(status.as_u16(), &body, retry_after));
+        }
+
+        endpoint.response_body(response).await.map_err(boxed_error)
+    }
+
+    pub async fn get_voices(&self) -> Result<Vec<Voice>, PleaseSpeakError> {
+        if let Some(voices) = self.init_voices.lock().unwrap().take() {
+            return Ok(voices);
+        }
+        let result = self.hit(elevenlabs_rs::GetVoices).await?;
+        Ok(voices_of(&result))
+    }
+
+    pub async fn get_account(&self) -> Result<Account, PleaseSpeakError> {
+        let user = self.hit(GetUser).await?;
+        Ok(Account {
+            name: user.first_name.filter(|name| !name.is_empty()),
+            tier: user.subscription.tier,
+            character_count: user.subscription.character_count,
+            character_limit: user.subscription.character_limit,
+            next_reset: user.subscription.next_character_count_reset_unix,
+        })
+    }
+
+    pub async fn get_models(&self) -> Result<Vec<Model>, PleaseSpeakError> {
+        let result = self.hit(ListModels).await?;
+        Ok(result
+            .into_iter()
+            .filter(|model| model.can_do_text_to_speech)
+            .map(|model| Model {
+                model_id: model.model_id,
+                model_name: model.name,
+                languages: model.languages.into_iter().map(|language| language.name).collect(),
+                max_characters: model.maximum_text_length_per_request as usize,
+            }).collect())
+    }
+
+    pub async fn get_voice_settings(&self, voice: &Voice) -> Result<VoiceSettings, PleaseSpeakError> {
+        self.hit(GetSettings { voice_id: voice.get_voice_id().to_string() }).await
+    }
+
+    pub async fn generate_speak(&self, request: SpeechRequest) -> Result<Bytes, PleaseSpeakError> {
+        let endpoint = Speak {
+            voice_id: request.voice.get_voice_id().to_string(),
+            body: request.into(),
+        };
+        self.hit(endpoint).await
+    }
+
+    pub async fn generate_stream(&self, request: SpeechRequest, chunks: Sender<Bytes>) -> Result<Bytes, PleaseSpeakError> {
+        let endpoint = SpeakStream {
+            voice_id: request.voice.get_voice_id().to_string(),
+            body: request.into(),
+        };
+        let mut response = self.hit(endpoint).await?;
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            bytes.extend_from_slice(&chunk);
+            let _ = chunks.send(chunk).await;
         }
+
+        Ok(Bytes::from(bytes))
+    }
+}
+
+/// Sorts the errors `elevenlabs_rs` boxes, when reading a body or building a request.
+fn boxed_error(error: Box<dyn std::error::Error + Send + Sync>) -> PleaseSpeakError {
+    match error.downcast::<reqwest::Error>() {
+        Ok(error) => PleaseSpeakError::from(*error),
+        Err(error) => PleaseSpeakError::Other(error.to_string()),
+    }
+}
+
+fn voices_of(result: &<elevenlabs_rs::GetVoices as Endpoint>::ResponseBody) -> Vec<Voice> {
+    result
+        .get_voices()
+        .iter()
+        .map(|voice| Voice {
+            voice_id: voice.get_voice_id().to_string(),
+            voice_name: voice.get_name().to_string(),
+        }).collect()
+}
+
+impl SpeechProvider for Elabs {
+    fn name(&self) -> &str {
+        "ElevenLabs"
     }
 
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            streaming: true,
+            model_selection: true,
+            voice_settings: true,
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.connected
+    }
+
+    fn output_format(&self) -> &str {
+        DEFAULT_OUTPUT_FORMAT
+    }
+
+    fn get_voices(&self) -> BoxFuture<'_, Result<Vec<Voice>, PleaseSpeakError>> {
+        Box::pin(Elabs::get_voices(self))
+    }
+
+    fn get_account(&self) -> BoxFuture<'_, Result<Account, PleaseSpeakError>> {
+        Box::pin(Elabs::get_account(self))
+    }
+
+    fn get_models(&self) -> BoxFuture<'_, Result<Vec<Model>, PleaseSpeakError>> {
+        Box::pin(Elabs::get_models(self))
+    }
+
+    fn get_voice_settings<'a>(&'a self, voice: &'a Voice) -> BoxFuture<'a, Result<VoiceSettings, PleaseSpeakError>> {
+        Box::pin(Elabs::get_voice_settings(self, voice))
+    }
+
+    fn generate_speak(&self, request: SpeechRequest) -> BoxFuture<'_, Result<Bytes, PleaseSpeakError>> {
+        Box::pin(Elabs::generate_speak(self, request))
+    }
+
+    fn generate_stream(&self, request: SpeechRequest, chunks: Sender<Bytes>) -> BoxFuture<'_, Result<Bytes, PleaseSpeakError>> {
+        Box::pin(Elabs::generate_stream(self, request, chunks))
+    }
 }
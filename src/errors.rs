@@ -1,44 +1,331 @@
-use async_channel::Receiver;
+use std::fmt;
+use std::time::Duration;
+use async_channel::{Receiver, Sender};
+use chrono::{DateTime, Local};
 use eframe::egui;
 use egui::Align2;
+use serde_json::Value;
 
+/// Errors kept in the log, the oldest are dropped past this.
+const MAX_LOGGED_ERRORS: usize = 500;
+
+/// Everything that can go wrong, sorted by what the user can do about it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PleaseSpeakError {
+    /// The API key is missing, wrong or lacks a permission.
+    Auth(String),
+    QuotaExceeded(String),
+    /// Too many requests, `retry_after` is how long the provider asked to wait.
+    RateLimited { message: String, retry_after: Option<Duration> },
+    InvalidVoice(String),
+    Network(String),
+    AudioDevice(String),
+    Filesystem(String),
+    /// The local API could not be served.
+    Server(String),
+    Other(String),
+}
+
+impl PleaseSpeakError {
+    pub fn get_name(&self) -> &str {
+        match self {
+            PleaseSpeakError::Auth(_) => "Authentication failed",
+            PleaseSpeakError::QuotaExceeded(_) => "Quota exceeded",
+            PleaseSpeakError::RateLimited { .. } => "Rate limited",
+            PleaseSpeakError::InvalidVoice(_) => "Invalid voice",
+            PleaseSpeakError::Network(_) => "Network error",
+            PleaseSpeakError::AudioDevice(_) => "Audio device error",
+            PleaseSpeakError::Filesystem(_) => "File error",
+            PleaseSpeakError::Server(_) => "Local API error",
+            PleaseSpeakError::Other(_) => "Error",
+        }
+    }
+
+    pub fn get_message(&self) -> &str {
+        match self {
+            PleaseSpeakError::Auth(message)
+            | PleaseSpeakError::QuotaExceeded(message)
+            | PleaseSpeakError::RateLimited { message, .. }
+            | PleaseSpeakError::InvalidVoice(message)
+            | PleaseSpeakError::Network(message)
+            | PleaseSpeakError::AudioDevice(message)
+            | PleaseSpeakError::Filesystem(message)
+            | PleaseSpeakError::Server(message)
+            | PleaseSpeakError::Other(message) => message,
+        }
+    }
+
+    /// What the user can try to get past the error.
+    pub fn get_suggestion(&self) -> Option<&str> {
+        match self {
+            PleaseSpeakError::Auth(_) => Some("Check the API key in File > Settings, or create a new one in your ElevenLabs profile."),
+            PleaseSpeakError::QuotaExceeded(_) => Some("Wait for the quota to reset or upgrade your plan, the account usage is shown under Test connection in the settings."),
+            PleaseSpeakError::RateLimited { .. } => Some("Wait a moment before generating again, or lower the concurrent jobs in the settings."),
+            PleaseSpeakError::InvalidVoice(_) => Some("The voice may have been deleted, pick another one in the voice list."),
+            PleaseSpeakError::Network(_) => Some("Check your internet connection, then retry."),
+            PleaseSpeakError::AudioDevice(_) => Some("Check that the output device is plugged in, or pick another one in the settings."),
+            PleaseSpeakError::Filesystem(_) => Some("Check that the folder exists and can be written to, it is set under Save to in the settings."),
+            PleaseSpeakError::Server(_) => Some("Another program may be using the port, pick another one under Local API in the settings."),
+            PleaseSpeakError::Other(_) => None,
+        }
+    }
+
+    /// Whether trying again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, PleaseSpeakError::RateLimited { .. } | PleaseSpeakError::Network(_))
+    }
+
+    /// Reads an error answer of the API, which explains itself in a `detail` object or string.
+    pub fn from_status(status: u16, body: &str, retry_after: Option<Duration>) -> Self {
+        let detail = serde_json::from_str::<Value>(body).ok().and_then(|body| body.get("detail").cloned());
+        let (kind, message) = match &detail {
+            Some(Value::Object(detail)) => (
+                detail.get("status").and_then(Value::as_str).unwrap_or_default().to_string(),
+                detail.get("message").and_then(Value::as_str).unwrap_or(body).to_string(),
+            ),
+            Some(Value::String(message)) => (String::new(), message.clone()),
+            _ => (String::new(), body.to_string()),
+        };
+        let message = if message.is_empty() { format!("The API answered with status {}", status) } else { message };
+
+        match (status, kind.as_str()) {
+            (_, "quota_exceeded") => PleaseSpeakError::QuotaExceeded(message),
+            (401 | 403, _) | (_, "invalid_api_key" | "missing_permissions") => PleaseSpeakError::Auth(message),
+            (429, _) | (_, "too_many_concurrent_requests" | "system_busy") => PleaseSpeakError::RateLimited { message, retry_after },
+            (_, "voice_not_found") => PleaseSpeakError::InvalidVoice(message),
+            (500..=599, _) => PleaseSpeakError::Network(format!("ElevenLabs is unavailable ({}): {}", status, message)),
+            _ => PleaseSpeakError::Other(format!("The API answered with status {}: {}", status, message)),
+        }
+    }
+}
+
+impl fmt::Display for PleaseSpeakError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.get_name(), self.get_message())
+    }
+}
+
+impl std::error::Error for PleaseSpeakError {}
+
+impl From<String> for PleaseSpeakError {
+    fn from(message: String) -> Self {
+        PleaseSpeakError::Other(message)
+    }
+}
+
+impl From<reqwest::Error> for PleaseSpeakError {
+    fn from(error: reqwest::Error) -> Self {
+        PleaseSpeakError::Network(error.to_string())
+    }
+}
+
+impl From<std::io::Error> for PleaseSpeakError {
+    fn from(error: std::io::Error) -> Self {
+        PleaseSpeakError::Filesystem(error.to_string())
+    }
+}
+
+/// What was being done when an error happened, every error is reported along with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorSource {
+    Api,
+    Playback,
+    Export,
+    History,
+    Secrets,
+    Server,
+    Soundboard,
+    Batch,
+    Project,
+}
+
+impl ErrorSource {
+    pub fn get_name(&self) -> &str {
+        match self {
+            ErrorSource::Api => "Api error",
+            ErrorSource::Playback => "Playback error",
+            ErrorSource::Export => "Export error",
+            ErrorSource::History => "History error",
+            ErrorSource::Secrets => "Secrets error",
+            ErrorSource::Server => "Local API error",
+            ErrorSource::Soundboard => "Soundboard error",
+            ErrorSource::Batch => "Batch error",
+            ErrorSource::Project => "Project error",
+        }
+    }
+}
+
+/// Where every part of the app reports its errors, see [`ErrorManager`].
+pub type ErrorSender = Sender<(ErrorSource, PleaseSpeakError)>;
+
+/// An error as it was reported, with where it came from.
+#[derive(Clone)]
+pub struct LoggedError {
+    pub source: ErrorSource,
+    pub error: PleaseSpeakError,
+    pub time: DateTime<Local>,
+}
+
+/// Every error reported since the app started, newest last.
+#[derive(Default)]
+pub struct ErrorLog {
+    entries: Vec<LoggedError>,
+}
+
+impl ErrorLog {
+    pub fn push(&mut self, source: ErrorSource, error: PleaseSpeakError) {
+        log::warn!("{}: {}", source.get_name(), error);
+        self.entries.push(LoggedError {
+            source,
+            error,
+            time: Local::now(),
+        });
+        if self.entries.len() > MAX_LOGGED_ERRORS {
+            self.entries.remove(0);
+        }
+    }
+
+    pub fn ui(&mut self, ctx: &egui::Context, open: &mut bool) {
+        let mut clear = false;
+
+        egui::Window::new("Error log")
+            .open(open)
+            .default_size([520.0, 300.0])
+            .show(ctx, |ui| {
+                if ui.button("Clear").clicked() {
+                    clear = true;
+                }
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for entry in self.entries.iter().rev() {
+                        ui.group(|ui| {
+                            ui.set_width(ui.available_width());
+                            ui.small(format!("{} · {}", entry.time.format("%Y-%m-%d %H:%M:%S"), entry.source.get_name()));
+                            ui.strong(entry.error.get_name());
+                            ui.label(entry.error.get_message());
+                            if let Some(suggestion) = entry.error.get_suggestion() {
+                                ui.weak(suggestion);
+                            }
+                        });
+                    }
+
+                    if self.entries.is_empty() {
+                        ui.label("No errors.");
+                    }
+                });
+            });
+
+        if clear {
+            self.entries.clear();
+        }
+    }
+}
+
+/// Receives the errors of the whole app, logs them and shows the newest one.
 #[derive(Clone)]
 pub struct ErrorManager {
-    error_rx: Receiver<String>,
-    last_error: Option<String>,
+    error_rx: Receiver<(ErrorSource, PleaseSpeakError)>,
+    last_error: Option<(ErrorSource, PleaseSpeakError)>,
     modal_open: bool,
-    name: String,
 }
 
 impl ErrorManager {
-    pub(crate) fn new(name: String, error_rx: Receiver<String>) -> Self {
+    pub(crate) fn new(error_rx: Receiver<(ErrorSource, PleaseSpeakError)>) -> Self {
         Self {
             error_rx,
             last_error: None,
             modal_open: false,
-            name,
         }
     }
 
-    pub fn update(&mut self, ctx: &egui::Context) {
-        if let Ok(error) = self.error_rx.try_recv() {
-            println!("[{}]: Error: {:?}", self.name, error);
-            self.last_error = Some(error);
+    /// Logs the errors received since the last frame and shows the newest one.
+    ///
+    /// Returns `true` when the user asked to see the whole log.
+    pub fn update(&mut self, ctx: &egui::Context, log: &mut ErrorLog) -> bool {
+        while let Ok((source, error)) = self.error_rx.try_recv() {
+            log.push(source, error.clone());
+            self.last_error = Some((source, error));
             self.modal_open = true;
         }
 
-        if let Some(error) = &self.last_error {
-            egui::Window::new(&self.name)
+        let mut show_log = false;
+        if let Some((source, error)) = &self.last_error {
+            egui::Window::new(source.get_name())
+                .id(egui::Id::new("error_modal"))
                 .resizable(false)
                 .pivot(Align2::CENTER_CENTER)
                 .open(&mut self.modal_open)
                 .show(ctx, |ui| {
-                    ui.label(format!("Error: {:?}", error));
+                    ui.strong(error.get_name());
+                    ui.label(error.get_message());
+                    if let Some(suggestion) = error.get_suggestion() {
+                        ui.weak(suggestion);
+                    }
+                    if ui.button("Show error log").clicked() {
+                        show_log = true;
+                    }
                 });
 
             if !self.modal_open {
                 self.last_error = None;
             }
         }
+
+        show_log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unauthorized_is_an_auth_error() {
+        let body = r#"{"detail":{"status":"invalid_api_key","message":"Invalid API key"}}"#;
+        assert_eq!(PleaseSpeakError::from_status(401, body, None), PleaseSpeakError::Auth("Invalid API key".to_string()));
+        assert_eq!(PleaseSpeakError::from_status(401, "", None), PleaseSpeakError::Auth("The API answered with status 401".to_string()));
+    }
+
+    #[test]
+    fn too_many_requests_keeps_retry_after() {
+        let body = r#"{"detail":{"status":"too_many_concurrent_requests","message":"Slow down"}}"#;
+        let error = PleaseSpeakError::from_status(429, body, Some(Duration::from_secs(7)));
+        assert_eq!(error, PleaseSpeakError::RateLimited { message: "Slow down".to_string(), retry_after: Some(Duration::from_secs(7)) });
+        assert!(error.is_transient());
+    }
+
+    #[test]
+    fn quota_exceeded_wins_over_the_status() {
+        let body = r#"{"detail":{"status":"quota_exceeded","message":"This request exceeds your quota"}}"#;
+        let error = PleaseSpeakError::from_status(401, body, None);
+        assert_eq!(error, PleaseSpeakError::QuotaExceeded("This request exceeds your quota".to_string()));
+        assert!(!error.is_transient());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unknown_voice_is_an_invalid_voice() {
+        let body = r#"{"detail":{"status":"voice_not_found","message":"A voice with that id was not found"}}"#;
+        assert_eq!(PleaseSpeakError::from_status(400, body, None), PleaseSpeakError::InvalidVoice("A voice with that id was not found".to_string()));
+        assert_eq!(PleaseSpeakError::from_status(404, body, None), PleaseSpeakError::InvalidVoice("A voice with that id was not found".to_string()));
+    }
+
+    #[test]
+    fn other_not_found_is_not_about_the_voice() {
+        let error = PleaseSpeakError::from_status(404, r#"{"detail":"Not found"}"#, None);
+        assert_eq!(error, PleaseSpeakError::Other("The API answered with status 404: Not found".to_string()));
+    }
+
+    #[test]
+    fn server_errors_are_transient() {
+        let error = PleaseSpeakError::from_status(503, "upstream unavailable", None);
+        assert_eq!(error, PleaseSpeakError::Network("ElevenLabs is unavailable (503): upstream unavailable".to_string()));
+        assert!(error.is_transient());
+    }
+
+    #[test]
+    fn other_statuses_keep_the_body() {
+        let error = PleaseSpeakError::from_status(422, r#"{"detail":"text is too long"}"#, None);
+        assert_eq!(error, PleaseSpeakError::Other("The API answered with status 422: text is too long".to_string()));
+    }
+}
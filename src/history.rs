@@ -0,0 +1,320 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{Local, TimeZone};
+use eframe::egui;
+use elevenlabs_rs::Bytes;
+use serde::{Deserialize, Serialize};
+use crate::app::APP_NAME;
+use crate::audio::extension_of;
+use crate::errors::PleaseSpeakError;
+use crate::provider::SpeechRequest;
+use crate::{Voice, VoiceSettings};
+
+const INDEX_FILE: &str = "index.ron";
+pub const DEFAULT_HISTORY_SIZE_MB: u64 = 500;
+
+/// One generation, as recorded in the history index.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub text: String,
+    pub voice: Voice,
+    pub model: String,
+    #[serde(default)]
+    pub settings: Option<VoiceSettings>,
+    /// Unix timestamp in milliseconds.
+    pub created_at: i64,
+    pub file_path: String,
+    pub duration: Option<f32>,
+    pub characters: usize,
+}
+
+impl HistoryEntry {
+    pub fn request(&self) -> SpeechRequest {
+        SpeechRequest {
+            text: self.text.clone(),
+            voice: self.voice.clone(),
+            model: self.model.clone(),
+            settings: self.settings.clone(),
+            previous_text: None,
+            next_text: None,
+        }
+    }
+
+    pub fn created_at_label(&self) -> String {
+        Local
+            .timestamp_millis_opt(self.created_at)
+            .single()
+            .map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+
+    fn matches(&self, search: &str) -> bool {
+        let search = search.to_lowercase();
+        self.text.to_lowercase().contains(&search) || self.voice.get_voice_name().to_lowercase().contains(&search)
+    }
+}
+
+/// What the user asked for from the history window, handled by the app.
+pub enum HistoryAction {
+    Play(Bytes),
+    Resave(HistoryEntry, Bytes),
+    Regenerate(HistoryEntry),
+}
+
+/// Every generation with its audio, kept in the app storage directory across restarts.
+///
+/// The oldest entries are pruned once their audio grows over `max_bytes`.
+pub struct History {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Vec<HistoryEntry>,
+    search: String,
+}
+
+impl History {
+    pub fn load(max_size_mb: u64) -> Self {
+        Self::open(eframe::storage_dir(APP_NAME).unwrap_or_else(std::env::temp_dir).join("history"), max_size_mb * 1024 * 1024)
+    }
+
+    fn open(dir: PathBuf, max_bytes: u64) -> Self {
+        let entries = fs::read_to_string(dir.join(INDEX_FILE))
+            .ok()
+            .and_then(|content| ron::from_str(&content).ok())
+            .unwrap_or_default();
+
+        Self {
+            dir,
+            max_bytes,
+            entries,
+            search: String::new(),
+        }
+    }
+
+    pub fn set_max_size_mb(&mut self, max_size_mb: u64) {
+        self.max_bytes = max_size_mb * 1024 * 1024;
+    }
+
+    /// Returns the number of entries and the total size of their audio in bytes.
+    pub fn usage(&self) -> (usize, u64) {
+        (self.entries.len(), self.entries.iter().map(audio_size).sum())
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// Stores the audio next to the index and appends the entry.
+    pub fn record(&mut self, request: &SpeechRequest, bytes: &Bytes, duration: Option<f32>) -> Result<HistoryEntry, PleaseSpeakError> {
+        fs::create_dir_all(&self.dir).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+
+        let id = self.entries.iter().map(|entry| entry.id + 1).max().unwrap_or(1);
+        let file_path = self.dir.join(format!("{}.{}", id, extension_of(bytes)));
+        fs::write(&file_path, bytes).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+
+        let entry = HistoryEntry {
+            id,
+            text: request.text.clone(),
+            voice: request.voice.clone(),
+            model: request.model.clone(),
+            settings: request.settings.clone(),
+            created_at: Local::now().timestamp_millis(),
+            file_path: file_path.display().to_string(),
+            duration,
+            characters: request.text.chars().count(),
+        };
+        self.entries.push(entry.clone());
+        self.prune()?;
+        self.save_index()?;
+
+        Ok(entry)
+    }
+
+    /// Deletes the oldest entries until the audio fits in `max_bytes`, the newest always stays.
+    fn prune(&mut self) -> Result<(), PleaseSpeakError> {
+        let mut total: u64 = self.entries.iter().map(audio_size).sum();
+        while total > self.max_bytes && self.entries.len() > 1 {
+            let entry = self.entries.remove(0);
+            total -= audio_size(&entry);
+            if Path::new(&entry.file_path).exists() {
+                fs::remove_file(&entry.file_path).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_audio(&self, entry: &HistoryEntry) -> Result<Bytes, PleaseSpeakError> {
+        fs::read(&entry.file_path)
+            .map(Bytes::from)
+            .map_err(|e| PleaseSpeakError::Filesystem(format!("{}: {}", entry.file_path, e)))
+    }
+
+    pub fn delete(&mut self, id: u64) -> Result<(), PleaseSpeakError> {
+        if let Some(index) = self.entries.iter().position(|entry| entry.id == id) {
+            let entry = self.entries.remove(index);
+            if Path::new(&entry.file_path).exists() {
+                fs::remove_file(&entry.file_path).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+            }
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    fn save_index(&self) -> Result<(), PleaseSpeakError> {
+        let content = ron::ser::to_string_pretty(&self.entries, ron::ser::PrettyConfig::default()).map_err(|e| PleaseSpeakError::Other(e.to_string()))?;
+        fs::write(self.dir.join(INDEX_FILE), content).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))
+    }
+
+    /// Draws the history window, newest first, and returns the action the user clicked.
+    pub fn ui(&mut self, ctx: &egui::Context, open: &mut bool) -> Result<Option<HistoryAction>, PleaseSpeakError> {
+        let mut action = None;
+        let mut delete = None;
+        let mut error = None;
+
+        egui::Window::new("History")
+            .open(open)
+            .default_size([560.0, 300.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Search:");
+                    ui.text_edit_singleline(&mut self.search);
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for entry in self.entries.iter().rev().filter(|entry| entry.matches(&self.search)) {
+                        ui.group(|ui| {
+                            ui.set_width(ui.available_width());
+                            ui.label(&entry.text);
+                            ui.small(format!(
+                                "{} · {} · {} · {} · {} characters",
+                                entry.voice.get_voice_name(),
+                                entry.model,
+                                entry.created_at_label(),
+                                entry.duration.map(|d| format!("{:.1}s", d)).unwrap_or_else(|| "?".to_string()),
+                                entry.characters,
+                            ));
+
+                            ui.horizontal(|ui| {
+                                if ui.button("Play").clicked() {
+                                    match self.read_audio(entry) {
+                                        Ok(bytes) => action = Some(HistoryAction::Play(bytes)),
+                                        Err(e) => error = Some(e),
+                                    }
+                                }
+                                if ui.button("Save").clicked() {
+                                    match self.read_audio(entry) {
+                                        Ok(bytes) => action = Some(HistoryAction::Resave(entry.clone(), bytes)),
+                                        Err(e) => error = Some(e),
+                                    }
+                                }
+                                if ui.button("Regenerate").clicked() {
+                                    action = Some(HistoryAction::Regenerate(entry.clone()));
+                                }
+                                if ui.button("Delete").clicked() {
+                                    delete = Some(entry.id);
+                                }
+                            });
+                        });
+                    }
+
+                    if self.entries.is_empty() {
+                        ui.label("Nothing generated yet.");
+                    }
+                });
+            });
+
+        if let Some(id) = delete {
+            self.delete(id)?;
+        }
+
+        match error {
+            Some(error) => Err(error),
+            None => Ok(action),
+        }
+    }
+}
+
+fn audio_size(entry: &HistoryEntry) -> u64 {
+    fs::metadata(&entry.file_path).map(|metadata| metadata.len()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("please_speak_history_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn request(text: &str, voice: &str) -> SpeechRequest {
+        SpeechRequest {
+            text: text.to_string(),
+            voice: Voice::new(format!("{}_id", voice), voice.to_string()),
+            model: "eleven_turbo_v2_5".to_string(),
+            settings: None,
+            previous_text: None,
+            next_text: None,
+        }
+    }
+
+    #[test]
+    fn record_keeps_the_audio_and_survives_a_reload() {
+        let dir = temp_dir("record");
+        let mut history = History::open(dir.clone(), u64::MAX);
+        let bytes = Bytes::from_static(b"ID3 audio");
+        let entry = history.record(&request("Hello there", "Clyde"), &bytes, Some(1.5)).unwrap();
+
+        assert_eq!((entry.id, entry.characters, entry.duration), (1, 11, Some(1.5)));
+        assert!(entry.file_path.ends_with("1.mp3"), "{}", entry.file_path);
+        assert_eq!(history.read_audio(&entry).unwrap(), bytes);
+        assert_eq!(entry.request(), request("Hello there", "Clyde"));
+
+        let second = history.record(&request("Again", "Clyde"), &bytes, None).unwrap();
+        assert_eq!(second.id, 2);
+        assert_eq!(History::open(dir, u64::MAX).entries(), &[entry, second]);
+    }
+
+    #[test]
+    fn delete_removes_the_entry_and_its_audio() {
+        let dir = temp_dir("delete");
+        let mut history = History::open(dir.clone(), u64::MAX);
+        let first = history.record(&request("One", "Clyde"), &Bytes::from_static(b"one"), None).unwrap();
+        let second = history.record(&request("Two", "Clyde"), &Bytes::from_static(b"two"), None).unwrap();
+
+        history.delete(first.id).unwrap();
+        history.delete(42).unwrap();
+        assert!(!Path::new(&first.file_path).exists());
+        assert_eq!(history.entries(), &[second.clone()]);
+        assert_eq!(History::open(dir, u64::MAX).entries(), &[second]);
+    }
+
+    #[test]
+    fn oldest_entries_are_pruned_past_the_limit() {
+        let mut history = History::open(temp_dir("prune"), 10);
+        let entries: Vec<HistoryEntry> = (0..4)
+            .map(|index| history.record(&request(&index.to_string(), "Clyde"), &Bytes::from_static(b"four"), None).unwrap())
+            .collect();
+
+        assert_eq!(history.entries(), &entries[2..]);
+        assert_eq!(history.usage(), (2, 8));
+        assert!(!Path::new(&entries[0].file_path).exists());
+
+        // A single entry over the limit is kept.
+        history.record(&request("Long", "Clyde"), &Bytes::from_static(b"far too long"), None).unwrap();
+        assert_eq!(history.usage(), (1, 12));
+    }
+
+    #[test]
+    fn search_matches_text_and_voice_ignoring_case() {
+        let mut history = History::open(temp_dir("search"), u64::MAX);
+        let entry = history.record(&request("Thanks for the follow!", "Clyde"), &Bytes::from_static(b"audio"), None).unwrap();
+
+        assert!(entry.matches("FOLLOW"));
+        assert!(entry.matches("clyde"));
+        assert!(entry.matches(""));
+        assert!(!entry.matches("Rachel"));
+    }
+}
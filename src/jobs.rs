@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+use async_channel::Sender as AsyncSender;
+use eframe::egui;
+use elevenlabs_rs::Bytes;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use crate::audio::{duration_of, AudioFormat};
+use crate::cache::SpeechCache;
+use crate::device::OutputRoute;
+use crate::errors::PleaseSpeakError;
+use crate::playback::Player;
+use crate::provider::{SpeechProvider, SpeechRequest};
+use crate::retry::{observe, Retry};
+use crate::segment::{generate_segments, split_text};
+
+pub const DEFAULT_MAX_CONCURRENT_JOBS: usize = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed(PleaseSpeakError),
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn get_name(&self) -> &str {
+        match self {
+            JobStatus::Queued => "Queued",
+            JobStatus::Running => "Running",
+            JobStatus::Done => "Done",
+            JobStatus::Failed(_) => "Failed",
+            JobStatus::Cancelled => "Cancelled",
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed(_) | JobStatus::Cancelled)
+    }
+}
+
+/// What a job generates and how.
+#[derive(Clone)]
+pub struct JobSpec {
+    pub request: SpeechRequest,
+    /// Skips the cache lookup.
+    pub force: bool,
+    pub cache: Option<SpeechCache>,
+    /// Longer texts are generated in segments.
+    pub max_characters: usize,
+    /// Plays the audio on these routes while it is generated, when the provider can stream.
+    pub stream_to: Option<Vec<OutputRoute>>,
+}
+
+#[derive(Clone)]
+pub struct JobResult {
+    pub bytes: Bytes,
+    pub duration: Option<f32>,
+    pub cached: bool,
+}
+
+#[derive(Clone)]
+pub struct Job {
+    pub id: u64,
+    pub spec: JobSpec,
+    pub status: JobStatus,
+    /// Segments done and total, for texts generated in segments.
+    pub progress: Option<(usize, usize)>,
+    /// The last retry of its requests, while the job still runs.
+    pub retry: Option<Retry>,
+    pub result: Option<JobResult>,
+}
+
+enum JobEvent {
+    Progress(u64, usize, usize),
+    Retrying(u64, Retry),
+    Finished(u64, Result<JobResult, PleaseSpeakError>),
+}
+
+/// Generation jobs, run on the shared runtime a few at a time.
+pub struct JobQueue {
+    runtime: Handle,
+    jobs: Vec<Job>,
+    tasks: HashMap<u64, JoinHandle<()>>,
+    /// Jobs cancelled since the last [`Self::update`], reported as finished there.
+    cancelled: Vec<Job>,
+    next_id: u64,
+    revision: u64,
+    events_tx: Sender<JobEvent>,
+    events_rx: Receiver<JobEvent>,
+}
+
+impl JobQueue {
+    pub fn new(runtime: Handle) -> Self {
+        let (events_tx, events_rx) = channel();
+
+        Self {
+            runtime,
+            jobs: Vec::new(),
+            tasks: HashMap::new(),
+            cancelled: Vec::new(),
+            next_id: 1,
+            revision: 0,
+            events_tx,
+            events_rx,
+        }
+    }
+
+    /// Jobs queued or running.
+    pub fn active(&self) -> usize {
+        self.jobs.iter().filter(|job| !job.status.is_finished()).count()
+    }
+
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// Increased on every change to the jobs, so a copy of them is only rebuilt when stale.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    /// Queues a job and returns its id, it starts on the next [`Self::update`].
+    pub fn submit(&mut self, spec: JobSpec) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.revision += 1;
+        self.jobs.push(Job {
+            id,
+            spec,
+            status: JobStatus::Queued,
+            progress: None,
+            retry: None,
+            result: None,
+        });
+        id
+    }
+
+    pub fn cancel(&mut self, id: u64) {
+        if let Some(task) = self.tasks.remove(&id) {
+            task.abort();
+        }
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id && !job.status.is_finished()) {
+            job.status = JobStatus::Cancelled;
+            self.cancelled.push(job.clone());
+            self.revision += 1;
+        }
+    }
+
+    /// Queues a failed or cancelled job again, under the same id.
+    pub fn retry(&mut self, id: u64) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            if matches!(job.status, JobStatus::Failed(_) | JobStatus::Cancelled) {
+                job.status = JobStatus::Queued;
+                job.progress = None;
+                job.retry = None;
+                job.result = None;
+                self.revision += 1;
+            }
+        }
+    }
+
+    /// Drops the audio of a finished job once whoever submitted it has taken it, the job stays listed.
+    pub fn release(&mut self, id: u64) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id && job.status.is_finished()) {
+            job.result = None;
+            self.revision += 1;
+        }
+    }
+
+    pub fn clear_finished(&mut self) {
+        self.jobs.retain(|job| !job.status.is_finished());
+        self.revision += 1;
+    }
+
+    /// Applies what the running jobs reported and starts queued jobs, at most `max_concurrent`
+    /// running at once. Returns the jobs that finished since the last call.
+    pub fn update(&mut self, provider: &Arc<dyn SpeechProvider>, player: &Player, max_concurrent: usize) -> Vec<Job> {
+        let mut finished = std::mem::take(&mut self.cancelled);
+
+        while let Ok(event) = self.events_rx.try_recv() {
+            let id = match &event {
+                JobEvent::Progress(id, _, _) | JobEvent::Retrying(id, _) | JobEvent::Finished(id, _) => *id,
+            };
+            // Jobs cancelled meanwhile keep their status.
+            let Some(job) = self.jobs.iter_mut().find(|job| job.id == id && job.status == JobStatus::Running) else {
+                continue;
+            };
+            self.revision += 1;
+
+            match event {
+                JobEvent::Progress(_, done, total) => job.progress = Some((done, total)),
+                JobEvent::Retrying(_, retry) => job.retry = Some(retry),
+                JobEvent::Finished(_, result) => {
+                    self.tasks.remove(&id);
+                    job.retry = None;
+                    match result {
+                        Ok(result) => {
+                            job.status = JobStatus::Done;
+                            job.result = Some(result);
+                        }
+                        Err(error) => job.status = JobStatus::Failed(error),
+                    }
+                    finished.push(job.clone());
+                }
+            }
+        }
+
+        if !provider.connected() {
+            return finished;
+        }
+
+        let running = self.jobs.iter().filter(|job| job.status == JobStatus::Running).count();
+        let startable: Vec<u64> = self.jobs.iter()
+            .filter(|job| job.status == JobStatus::Queued)
+            .take(max_concurrent.max(1).saturating_sub(running))
+            .map(|job| job.id)
+            .collect();
+        for id in startable {
+            self.start(id, provider, player);
+        }
+
+        finished
+    }
+
+    fn start(&mut self, id: u64, provider: &Arc<dyn SpeechProvider>, player: &Player) {
+        let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) else {
+            return;
+        };
+        job.status = JobStatus::Running;
+        self.revision += 1;
+
+        let spec = job.spec.clone();
+        let segments = split_text(&spec.request.text, spec.max_characters);
+
+        // Segments are stitched once all are generated, so only single requests are streamed.
+        let chunks = match &spec.stream_to {
+            Some(routes) if segments.len() == 1 && provider.capabilities().streaming => {
+                let (chunks_tx, chunks_rx) = async_channel::unbounded();
+                player.stream(chunks_rx, AudioFormat::from_output_format(provider.output_format()), routes.clone());
+                Some(chunks_tx)
+            }
+            _ => None,
+        };
+
+        let provider = provider.clone();
+        let events_tx = self.events_tx.clone();
+        let task = self.runtime.spawn(async move {
+            let progress_tx = events_tx.clone();
+            let progress = move |done, total| {
+                let _ = progress_tx.send(JobEvent::Progress(id, done, total));
+            };
+            let retry_tx = events_tx.clone();
+            let on_retry = move |retry| {
+                let _ = retry_tx.send(JobEvent::Retrying(id, retry));
+            };
+            let result = observe(on_retry, run(provider.as_ref(), &spec, &segments, chunks, progress)).await;
+            let _ = events_tx.send(JobEvent::Finished(id, result));
+        });
+        self.tasks.insert(id, task);
+    }
+
+    /// Draws the jobs window, newest first, with their status and controls.
+    ///
+    /// Returns the job queued again with its Retry button, for whoever submitted it to take it back.
+    pub fn ui(&mut self, ctx: &egui::Context, open: &mut bool) -> Option<u64> {
+        if self.active() > 0 {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+
+        let mut cancel = None;
+        let mut retry = None;
+        let mut clear = false;
+
+        egui::Window::new("Jobs")
+            .open(open)
+            .default_size([480.0, 260.0])
+            .show(ctx, |ui| {
+                if ui.button("Clear finished").clicked() {
+                    clear = true;
+                }
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for job in self.jobs.iter().rev() {
+                        ui.group(|ui| {
+                            ui.set_width(ui.available_width());
+                            let text: String = job.spec.request.text.chars().take(60).collect();
+                            ui.label(text);
+                            ui.small(format!(
+                                "#{} · {} · {} · {}",
+                                job.id,
+                                job.status.get_name(),
+                                job.spec.request.voice.get_voice_name(),
+                                job.spec.request.model,
+                            ));
+
+                            match (&job.status, job.progress) {
+                                (JobStatus::Running, Some((done, total))) => {
+                                    ui.add(egui::ProgressBar::new(done as f32 / total as f32).text(format!("Segment {} of {}", (done + 1).min(total), total)));
+                                }
+                                (JobStatus::Running, None) => {
+                                    ui.spinner();
+                                }
+                                (JobStatus::Failed(error), _) => {
+                                    ui.colored_label(ui.visuals().error_fg_color, error.to_string());
+                                }
+                                _ => {}
+                            }
+
+                            if let (JobStatus::Running, Some(retry)) = (&job.status, &job.retry) {
+                                ui.weak(format!(
+                                    "Attempt {} of {}, retrying after {:.1}s: {}",
+                                    retry.attempt + 1,
+                                    retry.max_attempts,
+                                    retry.delay.as_secs_f32(),
+                                    retry.error,
+                                ));
+                            }
+
+                            ui.horizontal(|ui| {
+                                if !job.status.is_finished() && ui.button("Cancel").clicked() {
+                                    cancel = Some(job.id);
+                                }
+                                if matches!(job.status, JobStatus::Failed(_) | JobStatus::Cancelled) && ui.button("Retry").clicked() {
+                                    retry = Some(job.id);
+                                }
+                            });
+                        });
+                    }
+
+                    if self.jobs.is_empty() {
+                        ui.label("No jobs.");
+                    }
+                });
+            });
+
+        if let Some(id) = cancel {
+            self.cancel(id);
+        }
+        if let Some(id) = retry {
+            self.retry(id);
+        }
+        if clear {
+            self.clear_finished();
+        }
+        retry
+    }
+}
+
+async fn run(
+    provider: &dyn SpeechProvider,
+    spec: &JobSpec,
+    segments: &[String],
+    chunks: Option<AsyncSender<Bytes>>,
+    progress: impl Fn(usize, usize),
+) -> Result<JobResult, PleaseSpeakError> {
+    let (bytes, cached) = if segments.len() > 1 {
+        generate_segments(provider, spec.cache.as_ref(), &spec.request, segments, spec.force, progress).await?
+    } else {
+        match (&spec.cache, chunks) {
+            (Some(cache), chunks) => cache.generate(provider, spec.request.clone(), spec.force, chunks).await?,
+            (None, Some(chunks)) => (provider.generate_stream(spec.request.clone(), chunks).await?, false),
+            (None, None) => (provider.generate_speak(spec.request.clone()).await?, false),
+        }
+    };
+
+    let duration = duration_of(&bytes).map(|d| d.as_secs_f32());
+    Ok(JobResult { bytes, duration, cached })
+}
@@ -1,10 +1,35 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod audio;
+mod batch;
+mod cache;
+pub mod cli;
 mod elabs;
 mod errors;
+mod history;
+mod jobs;
+mod naming;
 mod device;
+mod playback;
+mod project;
+mod provider;
+mod retry;
+mod secrets;
+mod segment;
+mod server;
+mod soundboard;
 
-pub use app::TtsApp;
-pub use elabs::{Elabs, Voice};
-pub use errors::ErrorManager;
+pub use app::{Configuration, TtsApp, APP_NAME};
+pub use audio::{AudioFormat, DecodedAudio, ExportFormat};
+pub use batch::{Batch, BatchAction, BatchRow, Manifest};
+pub use elabs::{Elabs, Model, Voice, VoiceSettings};
+pub use cache::SpeechCache;
+pub use errors::{ErrorLog, ErrorManager, ErrorSender, ErrorSource, PleaseSpeakError};
+pub use history::{History, HistoryAction, HistoryEntry};
+pub use naming::{output_path, render_template, sanitize_file_name, Collision};
+pub use playback::{PlaybackState, PlaybackStatus, Player};
+pub use project::{Project, ProjectAction, ProjectDocument, ProjectLine};
+pub use provider::{Account, Capabilities, ConnectionState, ProviderKind, SpeechProvider, SpeechRequest};
+pub use retry::{Retry, RetryPolicy};
+pub use soundboard::{Soundboard, SoundboardAction, SoundboardPad};
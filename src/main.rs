@@ -1,12 +1,17 @@
 #![warn(clippy::all, rust_2018_idioms)]
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use please_speak::TtsApp;
+use please_speak::{TtsApp, APP_NAME};
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result {
     env_logger::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(please_speak::cli::run(&args));
+    }
+
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([630.0, 390.0])
@@ -21,7 +26,7 @@ fn main() -> eframe::Result {
     };
 
     eframe::run_native(
-        "Please Speak",
+        APP_NAME,
         native_options,
         Box::new(move |cc| {
             let mut app = TtsApp::new(cc);
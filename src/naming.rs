@@ -0,0 +1,176 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::provider::SpeechRequest;
+
+pub const DEFAULT_FILE_NAME_TEMPLATE: &str = "{voice}_{date}_{time}";
+/// Longest `{text_slug}`, in characters.
+const TEXT_SLUG_LENGTH: usize = 40;
+/// Name given when a template leaves nothing usable.
+const FALLBACK_FILE_NAME: &str = "speech";
+
+/// What saving does when the file already exists.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum Collision {
+    /// Adds `_2`, `_3`, ... to the name until it is free.
+    #[default]
+    Increment,
+    Overwrite,
+}
+
+impl Collision {
+    pub const ALL: [Collision; 2] = [Collision::Increment, Collision::Overwrite];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            Collision::Increment => "Add a number",
+            Collision::Overwrite => "Overwrite",
+        }
+    }
+}
+
+/// Fills the tokens of `template` for `request`.
+///
+/// Tokens are `{voice}`, `{model}`, `{date}`, `{time}`, `{text_slug}`, `{hash}` and `{counter}`. Their
+/// values are sanitized, so only a `/` written in the template saves into subfolders, see [`output_path`].
+pub fn render_template(template: &str, request: &SpeechRequest, counter: u64) -> String {
+    let now = chrono::Local::now();
+    let tokens = [
+        ("{voice}", request.voice.get_voice_name().to_string()),
+        ("{model}", request.model.clone()),
+        ("{date}", now.format("%Y-%m-%d").to_string()),
+        ("{time}", now.format("%H-%M-%S").to_string()),
+        ("{text_slug}", text_slug(&request.text)),
+        ("{hash}", request_hash(request)),
+        ("{counter}", format!("{:04}", counter)),
+    ];
+    fill_tokens(template, &tokens)
+}
+
+/// Replaces each token of `template` by its value, sanitized for a file name.
+pub fn fill_tokens(template: &str, tokens: &[(&str, String)]) -> String {
+    tokens.iter().fold(template.to_string(), |name, (token, value)| name.replace(token, &sanitize_file_name(value)))
+}
+
+/// Where a file named `name` is saved under `dir`.
+///
+/// Each folder of `name` is sanitized and `..` dropped, so the path never leaves `dir`.
+pub fn output_path(dir: &Path, name: &str, extension: &str, collision: Collision) -> PathBuf {
+    let mut components: Vec<String> = name.split(['/', '\\'])
+        .map(str::trim)
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .map(sanitize_file_name)
+        .collect();
+    let stem = components.pop().unwrap_or_else(|| FALLBACK_FILE_NAME.to_string());
+    let folder = components.iter().fold(dir.to_path_buf(), |folder, component| folder.join(component));
+
+    let path = folder.join(format!("{}.{}", stem, extension));
+    if collision == Collision::Overwrite {
+        return path;
+    }
+
+    let mut path = path;
+    let mut number = 2;
+    while path.exists() {
+        path = folder.join(format!("{}_{}.{}", stem, number, extension));
+        number += 1;
+    }
+    path
+}
+
+/// Keeps letters, digits and `-_. ` so any id or voice name gives a valid file name.
+pub fn sanitize_file_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || "-_. ".contains(c) { c } else { '_' })
+        .collect();
+    let name = name.trim().trim_matches('.');
+    if name.is_empty() { "_".to_string() } else { name.to_string() }
+}
+
+/// The start of `text` in lowercase words joined by `-`.
+fn text_slug(text: &str) -> String {
+    let slug = text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-");
+    let slug: String = slug.chars().take(TEXT_SLUG_LENGTH).collect();
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Eight hex digits identifying everything that produced the audio.
+fn request_hash(request: &SpeechRequest) -> String {
+    let hash = Sha256::digest(ron::to_string(request).unwrap_or_default());
+    hash.iter().take(4).fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::Voice;
+
+    fn request(voice_name: &str, text: &str) -> SpeechRequest {
+        SpeechRequest {
+            text: text.to_string(),
+            voice: Voice::new("voice_id".to_string(), voice_name.to_string()),
+            model: "eleven_turbo_v2_5".to_string(),
+            settings: None,
+            previous_text: None,
+            next_text: None,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("please_speak_naming_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn sanitize_file_name_replaces_unsafe_characters() {
+        assert_eq!(sanitize_file_name("AC/DC"), "AC_DC");
+        assert_eq!(sanitize_file_name("a:b*c?\\"), "a_b_c__");
+        assert_eq!(sanitize_file_name("Zoë 2.0"), "Zoë 2.0");
+        assert_eq!(sanitize_file_name(".."), "_");
+        assert_eq!(sanitize_file_name(" .hidden. "), "hidden");
+        assert_eq!(sanitize_file_name(""), "_");
+    }
+
+    #[test]
+    fn token_values_do_not_create_folders() {
+        let name = render_template("{voice}/{text_slug}_{model}_{counter}", &request("AC/DC", "Hello, World!"), 7);
+        assert_eq!(name, "AC_DC/hello-world_eleven_turbo_v2_5_0007");
+
+        let path = output_path(Path::new("/out"), &name, "mp3", Collision::Overwrite);
+        assert_eq!(path, Path::new("/out/AC_DC/hello-world_eleven_turbo_v2_5_0007.mp3"));
+    }
+
+    #[test]
+    fn output_path_stays_inside_the_folder() {
+        let dir = Path::new("/out");
+        assert_eq!(output_path(dir, "../../etc/passwd", "wav", Collision::Overwrite), Path::new("/out/etc/passwd.wav"));
+        assert_eq!(output_path(dir, "/abs/name", "wav", Collision::Overwrite), Path::new("/out/abs/name.wav"));
+        assert_eq!(output_path(dir, "C:\\Windows\\name", "wav", Collision::Overwrite), Path::new("/out/C_/Windows/name.wav"));
+        assert_eq!(output_path(dir, "./a/./b", "wav", Collision::Overwrite), Path::new("/out/a/b.wav"));
+        assert_eq!(output_path(dir, "/../", "wav", Collision::Overwrite), Path::new("/out/speech.wav"));
+    }
+
+    #[test]
+    fn output_path_handles_collisions() {
+        let dir = temp_dir("collisions");
+        fs::write(dir.join("clip.mp3"), b"1").unwrap();
+        fs::write(dir.join("clip_2.mp3"), b"2").unwrap();
+
+        assert_eq!(output_path(&dir, "clip", "mp3", Collision::Overwrite), dir.join("clip.mp3"));
+        assert_eq!(output_path(&dir, "clip", "mp3", Collision::Increment), dir.join("clip_3.mp3"));
+        assert_eq!(output_path(&dir, "other", "mp3", Collision::Increment), dir.join("other.mp3"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
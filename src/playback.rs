@@ -0,0 +1,537 @@
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::time::Duration;
+use async_channel::Receiver as AsyncReceiver;
+use eframe::egui;
+use elevenlabs_rs::Bytes;
+use rodio::buffer::SamplesBuffer;
+use rodio::decoder::DecoderError;
+use rodio::{Decoder, OutputStream, Sink, Source};
+use crate::audio::{duration_of, AudioFormat};
+use crate::device::{OutputRoute, PSDevice};
+use crate::errors::{ErrorSender, ErrorSource, PleaseSpeakError};
+
+/// How often the playback thread reports the position back to the UI.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Length of the decoded blocks queued while streaming.
+const STREAM_BLOCK: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlaybackStatus {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlaybackState {
+    pub status: PlaybackStatus,
+    pub position: Duration,
+    pub duration: Option<Duration>,
+    pub volume: f32,
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        Self {
+            status: PlaybackStatus::Stopped,
+            position: Duration::ZERO,
+            duration: None,
+            volume: 1.0,
+        }
+    }
+}
+
+enum PlayerCommand {
+    Play(Bytes, Vec<OutputRoute>),
+    Stream(AsyncReceiver<Bytes>, Option<AudioFormat>, Vec<OutputRoute>),
+    Pause,
+    Resume,
+    Stop,
+    Seek(Duration),
+    Volume(f32),
+}
+
+/// A route opened for playback, its sink is kept in step with the others.
+struct RouteOutput {
+    _stream: OutputStream,
+    sink: Sink,
+    /// The route volume, multiplied with the player volume.
+    volume: f32,
+}
+
+/// Plays audio on a background thread that owns the rodio outputs, so the UI never blocks.
+pub struct Player {
+    command_tx: Sender<PlayerCommand>,
+    state: Arc<Mutex<PlaybackState>>,
+}
+
+impl Player {
+    pub fn new(error_tx: ErrorSender) -> Self {
+        let (command_tx, command_rx) = channel();
+        let state = Arc::new(Mutex::new(PlaybackState::default()));
+
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            let mut outputs: Vec<RouteOutput> = Vec::new();
+
+            loop {
+                let command = match command_rx.recv_timeout(POLL_INTERVAL) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+
+                // Opening devices and decoding take a while, the state is only locked to publish
+                // the outcome so the UI reading it never waits on them.
+                match command {
+                    Some(PlayerCommand::Play(bytes, routes)) => {
+                        outputs.clear();
+                        let volume = thread_state.lock().unwrap().volume;
+                        let result = start(&bytes, &routes, volume, &error_tx);
+
+                        let mut state = thread_state.lock().unwrap();
+                        match result {
+                            Ok((started, duration)) => {
+                                outputs = started;
+                                state.status = PlaybackStatus::Playing;
+                                state.position = Duration::ZERO;
+                                state.duration = duration;
+                                drop(state);
+
+                                // Decoding the whole clip is only needed when its header does not tell the length.
+                                if duration.is_none() {
+                                    let duration = duration_of(&bytes);
+                                    thread_state.lock().unwrap().duration = duration;
+                                }
+                            }
+                            Err(error) => {
+                                state.status = PlaybackStatus::Stopped;
+                                let _ = error_tx.send_blocking((ErrorSource::Playback, PleaseSpeakError::AudioDevice(error)));
+                            }
+                        }
+                    }
+                    Some(PlayerCommand::Stream(chunks, format, routes)) => {
+                        outputs.clear();
+                        let volume = thread_state.lock().unwrap().volume;
+                        let result = start_stream(chunks, format, &routes, volume, error_tx.clone());
+
+                        let mut state = thread_state.lock().unwrap();
+                        match result {
+                            Ok(started) => {
+                                outputs = started;
+                                state.status = PlaybackStatus::Playing;
+                                state.position = Duration::ZERO;
+                                state.duration = None;
+                            }
+                            Err(error) => {
+                                state.status = PlaybackStatus::Stopped;
+                                let _ = error_tx.send_blocking((ErrorSource::Playback, PleaseSpeakError::AudioDevice(error)));
+                            }
+                        }
+                    }
+                    Some(PlayerCommand::Pause) => {
+                        if !outputs.is_empty() {
+                            outputs.iter().for_each(|output| output.sink.pause());
+                            thread_state.lock().unwrap().status = PlaybackStatus::Paused;
+                        }
+                    }
+                    Some(PlayerCommand::Resume) => {
+                        if !outputs.is_empty() {
+                            outputs.iter().for_each(|output| output.sink.play());
+                            thread_state.lock().unwrap().status = PlaybackStatus::Playing;
+                        }
+                    }
+                    Some(PlayerCommand::Stop) => {
+                        outputs.clear();
+                        let mut state = thread_state.lock().unwrap();
+                        state.status = PlaybackStatus::Stopped;
+                        state.position = Duration::ZERO;
+                    }
+                    Some(PlayerCommand::Seek(position)) => {
+                        for output in &outputs {
+                            if let Err(error) = output.sink.try_seek(position) {
+                                let _ = error_tx.send_blocking((ErrorSource::Playback, PleaseSpeakError::AudioDevice(error.to_string())));
+                            }
+                        }
+                    }
+                    Some(PlayerCommand::Volume(volume)) => {
+                        for output in &outputs {
+                            output.sink.set_volume(volume * output.volume);
+                        }
+                        thread_state.lock().unwrap().volume = volume;
+                    }
+                    None => {}
+                }
+
+                if let Some(first) = outputs.first() {
+                    if outputs.iter().all(|output| output.sink.empty()) {
+                        outputs.clear();
+                        let mut state = thread_state.lock().unwrap();
+                        state.status = PlaybackStatus::Stopped;
+                        state.position = Duration::ZERO;
+                    } else {
+                        let position = first.sink.get_pos();
+                        thread_state.lock().unwrap().position = position;
+                    }
+                }
+            }
+        });
+
+        Self { command_tx, state }
+    }
+
+    /// Plays `bytes` on every route at once, or on the system default output when there are none.
+    pub fn play(&self, bytes: Bytes, routes: Vec<OutputRoute>) {
+        let _ = self.command_tx.send(PlayerCommand::Play(bytes, routes));
+    }
+
+    /// Plays audio as its chunks arrive, until `chunks` is closed.
+    ///
+    /// The chunks must add up to one file, as sent by [`crate::SpeechProvider::generate_stream`].
+    /// Its `format` should be given when known, detecting it may need the whole file.
+    pub fn stream(&self, chunks: AsyncReceiver<Bytes>, format: Option<AudioFormat>, routes: Vec<OutputRoute>) {
+        let _ = self.command_tx.send(PlayerCommand::Stream(chunks, format, routes));
+    }
+
+    pub fn pause(&self) {
+        let _ = self.command_tx.send(PlayerCommand::Pause);
+    }
+
+    pub fn resume(&self) {
+        let _ = self.command_tx.send(PlayerCommand::Resume);
+    }
+
+    pub fn stop(&self) {
+        let _ = self.command_tx.send(PlayerCommand::Stop);
+    }
+
+    pub fn seek(&self, position: Duration) {
+        let _ = self.command_tx.send(PlayerCommand::Seek(position));
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        let _ = self.command_tx.send(PlayerCommand::Volume(volume));
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Draws the transport controls and the progress bar.
+    pub fn ui(&self, ui: &mut egui::Ui) {
+        let state = self.state();
+
+        ui.horizontal(|ui| {
+            match state.status {
+                PlaybackStatus::Playing => {
+                    if ui.button("Pause").clicked() {
+                        self.pause();
+                    }
+                }
+                PlaybackStatus::Paused => {
+                    if ui.button("Resume").clicked() {
+                        self.resume();
+                    }
+                }
+                PlaybackStatus::Stopped => {
+                    ui.add_enabled(false, egui::Button::new("Pause"));
+                }
+            }
+
+            if ui.add_enabled(state.status != PlaybackStatus::Stopped, egui::Button::new("Stop")).clicked() {
+                self.stop();
+            }
+
+            let mut volume = state.volume;
+            if ui.add(egui::Slider::new(&mut volume, 0.0..=1.5).text("Volume")).changed() {
+                self.set_volume(volume);
+            }
+        });
+
+        let total = state.duration.unwrap_or(state.position).as_secs_f32();
+        let mut position = state.position.as_secs_f32();
+        ui.horizontal(|ui| {
+            ui.label(format!("{} / {}", format_time(position), format_time(total)));
+            ui.spacing_mut().slider_width = ui.available_width();
+            let slider = egui::Slider::new(&mut position, 0.0..=total.max(0.01)).show_value(false);
+            // Streamed audio has no known length and cannot be seeked.
+            let seekable = state.status != PlaybackStatus::Stopped && state.duration.is_some();
+            if ui.add_enabled(seekable, slider).changed() {
+                self.seek(Duration::from_secs_f32(position));
+            }
+        });
+
+        if state.status == PlaybackStatus::Playing {
+            ui.ctx().request_repaint_after(POLL_INTERVAL);
+        }
+    }
+}
+
+fn start(
+    bytes: &Bytes,
+    routes: &[OutputRoute],
+    volume: f32,
+    error_tx: &ErrorSender,
+) -> Result<(Vec<RouteOutput>, Option<Duration>), String> {
+    let outputs = open_routes(routes, volume, error_tx)?;
+
+    let mut duration = None;
+    for output in &outputs {
+        let source = Decoder::new(Cursor::new(bytes.clone())).map_err(|e| e.to_string())?;
+        duration = duration.or(source.total_duration());
+        output.sink.append(source);
+    }
+    outputs.iter().for_each(|output| output.sink.play());
+
+    Ok((outputs, duration))
+}
+
+/// Plays `bytes` on every route and waits for the end, for when there is no UI to keep going.
+pub fn play_blocking(bytes: &Bytes, routes: &[OutputRoute]) -> Result<(), PleaseSpeakError> {
+    let (error_tx, error_rx) = async_channel::unbounded();
+    let result = start(bytes, routes, 1.0, &error_tx);
+    while let Ok((_, error)) = error_rx.try_recv() {
+        eprintln!("Warning: {}", error.get_message());
+    }
+
+    let (outputs, _) = result.map_err(PleaseSpeakError::AudioDevice)?;
+    outputs.iter().for_each(|output| output.sink.sleep_until_end());
+    Ok(())
+}
+
+fn open(device: Option<&PSDevice>, volume: f32) -> Result<(OutputStream, Sink), String> {
+    let (stream, handle) = match device {
+        Some(device) => OutputStream::try_from_device(&device.get_device().map_err(|e| e.get_message().to_string())?),
+        None => OutputStream::try_default(),
+    }.map_err(|e| e.to_string())?;
+
+    let sink = Sink::try_new(&handle).map_err(|e| e.to_string())?;
+    sink.set_volume(volume);
+
+    Ok((stream, sink))
+}
+
+/// Opens a paused sink on each route, or on the default output when there are none, so they
+/// can be started together.
+///
+/// Routes that fail to open are reported and skipped, it only fails when none opened.
+fn open_routes(routes: &[OutputRoute], volume: f32, error_tx: &ErrorSender) -> Result<Vec<RouteOutput>, String> {
+    if routes.is_empty() {
+        let (stream, sink) = open(None, volume)?;
+        sink.pause();
+        return Ok(vec![RouteOutput { _stream: stream, sink, volume: 1.0 }]);
+    }
+
+    let mut outputs = Vec::new();
+    let mut last_error = None;
+    for route in routes {
+        match open(Some(&route.device), volume * route.volume) {
+            Ok((stream, sink)) => {
+                sink.pause();
+                outputs.push(RouteOutput { _stream: stream, sink, volume: route.volume });
+            }
+            Err(error) => {
+                let message = format!("{}: {}", route.device.get_device_name(), error);
+                let _ = error_tx.send_blocking((ErrorSource::Playback, PleaseSpeakError::AudioDevice(message.clone())));
+                last_error = Some(message);
+            }
+        }
+    }
+
+    match (outputs.is_empty(), last_error) {
+        (true, Some(error)) => Err(error),
+        _ => Ok(outputs),
+    }
+}
+
+/// Queues the audio on each route and decodes the chunks into them on another thread, so the
+/// output callbacks never wait on the network.
+fn start_stream(
+    chunks: AsyncReceiver<Bytes>,
+    format: Option<AudioFormat>,
+    routes: &[OutputRoute],
+    volume: f32,
+    error_tx: ErrorSender,
+) -> Result<Vec<RouteOutput>, String> {
+    let outputs = open_routes(routes, volume, &error_tx)?;
+    let queues: Vec<_> = outputs.iter()
+        .map(|output| {
+            let (queue, queue_output) = rodio::queue::queue::<i16>(true);
+            output.sink.append(queue_output);
+            queue
+        })
+        .collect();
+    outputs.iter().for_each(|output| output.sink.play());
+
+    std::thread::spawn(move || {
+        let append = |channels, sample_rate, samples: &[i16]| {
+            for queue in &queues {
+                queue.append(SamplesBuffer::new(channels, sample_rate, samples.to_vec()));
+            }
+        };
+
+        let mut reader = StreamReader::new(chunks);
+        if reader.wait_for_data() {
+            match stream_decoder(reader, format) {
+                Ok(decoder) => {
+                    let channels = decoder.channels();
+                    let sample_rate = decoder.sample_rate();
+                    let block = (sample_rate as f32 * STREAM_BLOCK.as_secs_f32()) as usize * channels as usize;
+
+                    let mut samples = Vec::with_capacity(block);
+                    for sample in decoder {
+                        samples.push(sample);
+                        if samples.len() == block {
+                            append(channels, sample_rate, &samples);
+                            samples.clear();
+                        }
+                    }
+                    if !samples.is_empty() {
+                        append(channels, sample_rate, &samples);
+                    }
+                }
+                Err(error) => {
+                    let _ = error_tx.send_blocking((ErrorSource::Playback, PleaseSpeakError::AudioDevice(error.to_string())));
+                }
+            }
+        }
+        queues.iter().for_each(|queue| queue.set_keep_alive_if_empty(false));
+    });
+
+    Ok(outputs)
+}
+
+/// Builds the decoder for the stream as soon as its first bytes are there.
+///
+/// Detecting the format tries Vorbis before MP3, which reads to the end of the stream looking
+/// for an Ogg page, so the known format is used instead.
+fn stream_decoder(reader: StreamReader, format: Option<AudioFormat>) -> Result<Decoder<StreamReader>, DecoderError> {
+    match format {
+        Some(AudioFormat::Mp3) => Decoder::new_mp3(reader),
+        Some(AudioFormat::Wav) => Decoder::new_wav(reader),
+        Some(AudioFormat::Flac) => Decoder::new_flac(reader),
+        Some(AudioFormat::Ogg) => Decoder::new_vorbis(reader),
+        None => Decoder::new(reader),
+    }
+}
+
+/// Reads a file while it is still arriving, blocking until the next chunk when it runs out.
+struct StreamReader {
+    chunks: AsyncReceiver<Bytes>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl StreamReader {
+    fn new(chunks: AsyncReceiver<Bytes>) -> Self {
+        Self {
+            chunks,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Appends the next chunk, returns `false` once every chunk has been received.
+    fn receive(&mut self) -> bool {
+        match self.chunks.recv_blocking() {
+            Ok(chunk) => {
+                self.buffer.extend_from_slice(&chunk);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn wait_for_data(&mut self) -> bool {
+        while self.buffer.is_empty() {
+            if !self.receive() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            if !self.receive() {
+                return Ok(0);
+            }
+        }
+
+        let read = buf.len().min(self.buffer.len() - self.position);
+        buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            // The end is not known before the last chunk, waiting for it would stall playback.
+            SeekFrom::End(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "the end of a stream is not known yet")),
+        };
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the stream"));
+        }
+
+        self.position = position as usize;
+        Ok(self.position as u64)
+    }
+}
+
+fn format_time(seconds: f32) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Silent MPEG-1 Layer III frames, 128 kbps at 44.1 kHz in mono.
+    fn mp3_frames(count: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0xC4]);
+        frame.repeat(count)
+    }
+
+    #[test]
+    fn stream_decoder_starts_on_the_first_chunk() {
+        let (chunks_tx, chunks_rx) = async_channel::unbounded();
+        chunks_tx.send_blocking(Bytes::from(mp3_frames(20))).unwrap();
+
+        let (built_tx, built_rx) = channel();
+        std::thread::spawn(move || {
+            let mut reader = StreamReader::new(chunks_rx);
+            assert!(reader.wait_for_data());
+            let decoder = stream_decoder(reader, Some(AudioFormat::Mp3)).map(|decoder| (decoder.channels(), decoder.sample_rate()));
+            let _ = built_tx.send(decoder.map_err(|e| e.to_string()));
+        });
+
+        // The channel stays open, so this only passes if the decoder did not wait for the end.
+        let built = built_rx.recv_timeout(Duration::from_secs(5)).expect("the decoder waited for the end of the stream");
+        assert_eq!(built, Ok((1, 44100)));
+        drop(chunks_tx);
+    }
+
+    #[test]
+    fn stream_reader_cannot_seek_from_the_end() {
+        let (chunks_tx, chunks_rx) = async_channel::unbounded();
+        chunks_tx.send_blocking(Bytes::from_static(b"abcd")).unwrap();
+        let mut reader = StreamReader::new(chunks_rx);
+
+        let error = reader.seek(SeekFrom::End(0)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert_eq!(reader.seek(SeekFrom::Start(2)).unwrap(), 2);
+        let mut rest = [0u8; 2];
+        reader.read_exact(&mut rest).unwrap();
+        assert_eq!(&rest, b"cd");
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::{Duration, Instant};
+use eframe::egui;
+use elevenlabs_rs::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use crate::app::APP_NAME;
+use crate::audio::DecodedAudio;
+use crate::errors::PleaseSpeakError;
+use crate::provider::SpeechRequest;
+use crate::{Voice, VoiceSettings};
+
+const DEFAULT_PROJECT_FILE: &str = "project.ron";
+const DEFAULT_PAUSE: f32 = 0.5;
+/// Longest an edit waits before the project file is written.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// A line of a project, said by its own voice.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct ProjectLine {
+    #[serde(default)]
+    pub id: u64,
+    pub text: String,
+    pub voice: Voice,
+    pub model: String,
+    #[serde(default)]
+    pub settings: Option<VoiceSettings>,
+    /// Silence after the line, in seconds.
+    #[serde(default)]
+    pub pause: f32,
+}
+
+impl ProjectLine {
+    pub fn request(&self) -> SpeechRequest {
+        SpeechRequest {
+            text: self.text.clone(),
+            voice: self.voice.clone(),
+            model: self.model.clone(),
+            settings: self.settings.clone(),
+            previous_text: None,
+            next_text: None,
+        }
+    }
+}
+
+/// What a project file holds.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct ProjectDocument {
+    pub name: String,
+    pub lines: Vec<ProjectLine>,
+}
+
+impl ProjectDocument {
+    /// Gives a new id to lines without one or sharing one, as in files written by hand.
+    fn allocate_ids(&mut self) {
+        let mut next = self.lines.iter().map(|line| line.id + 1).max().unwrap_or(1);
+        let mut seen = HashSet::new();
+        for line in &mut self.lines {
+            if line.id == 0 || !seen.insert(line.id) {
+                line.id = next;
+                next += 1;
+            }
+        }
+    }
+}
+
+/// What the user asked for from the project window, handled by the app.
+pub enum ProjectAction {
+    /// Renders these lines, bypassing the cache when set.
+    Render(Vec<ProjectLine>, bool),
+    Play(Bytes),
+    /// Saves the mix of every line under the project name.
+    Export(String, Bytes),
+}
+
+/// What to do with a mix once done.
+#[derive(Clone, Copy)]
+enum MixTarget {
+    Play,
+    Export,
+}
+
+/// Several voices speaking in turn, rendered line by line and mixed into one track.
+pub struct Project {
+    runtime: Handle,
+    path: String,
+    /// The path typed in the window, only used once opened or saved as.
+    path_input: String,
+    document: ProjectDocument,
+    /// When the first edit not written yet was made.
+    edited_at: Option<Instant>,
+    /// Audio by line, with the request it was rendered from so edited lines show as stale.
+    audio: HashMap<u64, (SpeechRequest, Bytes)>,
+    rendering: HashSet<u64>,
+    failed: HashMap<u64, String>,
+    mixing: bool,
+    mix_tx: Sender<(MixTarget, Result<Bytes, PleaseSpeakError>)>,
+    mix_rx: Receiver<(MixTarget, Result<Bytes, PleaseSpeakError>)>,
+}
+
+impl Project {
+    /// Opens the project at `path`, or an empty one when it cannot be read.
+    pub fn load(runtime: Handle, path: &str) -> Self {
+        let (mix_tx, mix_rx) = channel();
+        let path = if path.is_empty() {
+            eframe::storage_dir(APP_NAME).unwrap_or_else(std::env::temp_dir).join(DEFAULT_PROJECT_FILE).display().to_string()
+        } else {
+            path.to_string()
+        };
+
+        let mut project = Self {
+            runtime,
+            path_input: path.clone(),
+            path,
+            document: ProjectDocument::default(),
+            edited_at: None,
+            audio: HashMap::new(),
+            rendering: HashSet::new(),
+            failed: HashMap::new(),
+            mixing: false,
+            mix_tx,
+            mix_rx,
+        };
+        if let Ok(document) = read_document(&project.path) {
+            project.document = document;
+        }
+        project
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn rendering(&mut self, id: u64) {
+        self.rendering.insert(id);
+        self.failed.remove(&id);
+    }
+
+    pub fn rendered(&mut self, id: u64, request: SpeechRequest, bytes: Bytes) {
+        self.rendering.remove(&id);
+        self.failed.remove(&id);
+        self.audio.insert(id, (request, bytes));
+    }
+
+    pub fn render_failed(&mut self, id: u64, error: String) {
+        self.rendering.remove(&id);
+        self.failed.insert(id, error);
+    }
+
+    /// The audio of a line, when rendered from what the line says now.
+    fn line_audio(&self, line: &ProjectLine) -> Option<&Bytes> {
+        self.audio.get(&line.id)
+            .filter(|(request, _)| *request == line.request())
+            .map(|(_, bytes)| bytes)
+    }
+
+    fn stale_lines(&self) -> Vec<ProjectLine> {
+        self.document.lines.iter()
+            .filter(|line| self.line_audio(line).is_none() && !self.rendering.contains(&line.id))
+            .cloned()
+            .collect()
+    }
+
+    fn next_id(&self) -> u64 {
+        self.document.lines.iter().map(|line| line.id + 1).max().unwrap_or(1)
+    }
+
+    fn open(&mut self, path: &str) -> Result<(), PleaseSpeakError> {
+        self.save_pending()?;
+        self.document = read_document(path)?;
+        self.path = path.to_string();
+        self.edited_at = None;
+        self.audio.clear();
+        self.failed.clear();
+        Ok(())
+    }
+
+    /// Writes the edits made since the last save, if any.
+    pub fn save_pending(&mut self) -> Result<(), PleaseSpeakError> {
+        if self.edited_at.take().is_none() {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), PleaseSpeakError> {
+        if let Some(dir) = Path::new(&self.path).parent() {
+            fs::create_dir_all(dir).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+        }
+        let content = ron::ser::to_string_pretty(&self.document, ron::ser::PrettyConfig::default()).map_err(|e| PleaseSpeakError::Other(e.to_string()))?;
+        fs::write(&self.path, content).map_err(|e| PleaseSpeakError::Filesystem(format!("{}: {}", self.path, e)))
+    }
+
+    /// Mixes every line in order with its pause in the background.
+    fn mix(&mut self, target: MixTarget) {
+        let parts: Vec<(Bytes, f32)> = self.document.lines.iter()
+            .filter_map(|line| Some((self.line_audio(line)?.clone(), line.pause)))
+            .collect();
+
+        self.mixing = true;
+        let tx = self.mix_tx.clone();
+        self.runtime.spawn_blocking(move || {
+            let _ = tx.send((target, mix(parts)));
+        });
+    }
+
+    /// Draws the project window and returns the action the user clicked.
+    ///
+    /// New lines start with the voice, model and settings of `template`.
+    pub fn ui(&mut self, ctx: &egui::Context, open: &mut bool, voices: &[Voice], template: &SpeechRequest) -> Result<Option<ProjectAction>, PleaseSpeakError> {
+        let mut action = None;
+        let mut changed = false;
+        let mut moved = None;
+        let mut delete = None;
+        let mut opened = false;
+        let mut saved_as = false;
+
+        if let Ok((target, result)) = self.mix_rx.try_recv() {
+            self.mixing = false;
+            let bytes = result?;
+            action = Some(match target {
+                MixTarget::Play => ProjectAction::Play(bytes),
+                MixTarget::Export => ProjectAction::Export(self.document.name.clone(), bytes),
+            });
+        }
+        if self.mixing || !self.rendering.is_empty() {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+
+        egui::Window::new("Project")
+            .open(open)
+            .default_size([640.0, 380.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("File:");
+                    ui.text_edit_singleline(&mut self.path_input);
+                    opened = ui.button("Open").clicked();
+                    saved_as = ui.button("Save as").clicked();
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Name:");
+                    changed |= ui.text_edit_singleline(&mut self.document.name).changed();
+                });
+                ui.separator();
+
+                let stale = self.stale_lines();
+                let ready = !self.document.lines.is_empty() && stale.is_empty() && self.rendering.is_empty();
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(!stale.is_empty(), egui::Button::new(format!("Render ({})", stale.len()))).clicked() {
+                        action = Some(ProjectAction::Render(stale.clone(), false));
+                    }
+                    if ui.add_enabled(ready && !self.mixing, egui::Button::new("Play all")).clicked() {
+                        self.mix(MixTarget::Play);
+                    }
+                    if ui.add_enabled(ready && !self.mixing, egui::Button::new("Export")).clicked() {
+                        self.mix(MixTarget::Export);
+                    }
+                    if self.mixing || !self.rendering.is_empty() {
+                        ui.spinner();
+                    }
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    let count = self.document.lines.len();
+                    for index in 0..count {
+                        let line = &mut self.document.lines[index];
+                        let audio = self.audio.get(&line.id)
+                            .filter(|(request, _)| *request == line.request())
+                            .map(|(_, bytes)| bytes.clone());
+
+                        ui.group(|ui| {
+                            ui.set_width(ui.available_width());
+                            ui.horizontal(|ui| {
+                                ui.label(format!("{}.", index + 1));
+                                egui::ComboBox::from_id_source(("project_line_voice", line.id))
+                                    .selected_text(line.voice.get_voice_name())
+                                    .show_ui(ui, |ui| {
+                                        for voice in voices {
+                                            changed |= ui.selectable_value(&mut line.voice, voice.clone(), voice.get_voice_name()).changed();
+                                        }
+                                    });
+                                ui.label("Pause:");
+                                changed |= ui.add(egui::DragValue::new(&mut line.pause).range(0.0..=10.0).speed(0.05).suffix(" s")).changed();
+
+                                if self.rendering.contains(&line.id) {
+                                    ui.spinner();
+                                } else if let Some(error) = self.failed.get(&line.id) {
+                                    ui.colored_label(ui.visuals().error_fg_color, "Failed").on_hover_text(error);
+                                } else if audio.is_none() {
+                                    ui.weak("Not rendered");
+                                }
+                            });
+
+                            changed |= ui.add(egui::TextEdit::multiline(&mut line.text).desired_rows(2).desired_width(f32::INFINITY)).changed();
+
+                            ui.horizontal(|ui| {
+                                if ui.add_enabled(index > 0, egui::Button::new("▲")).clicked() {
+                                    moved = Some((index, index - 1));
+                                }
+                                if ui.add_enabled(index + 1 < count, egui::Button::new("▼")).clicked() {
+                                    moved = Some((index, index + 1));
+                                }
+                                if let Some(bytes) = &audio {
+                                    if ui.button("Play").clicked() {
+                                        action = Some(ProjectAction::Play(bytes.clone()));
+                                    }
+                                }
+                                if ui.add_enabled(!self.rendering.contains(&line.id), egui::Button::new("Regenerate")).clicked() {
+                                    action = Some(ProjectAction::Render(vec![line.clone()], true));
+                                }
+                                if ui.button("Use main window settings").on_hover_text("Takes the model and voice settings of the main window").clicked() {
+                                    line.model = template.model.clone();
+                                    line.settings = template.settings.clone();
+                                    changed = true;
+                                }
+                                if ui.button("Delete").clicked() {
+                                    delete = Some(index);
+                                }
+                            });
+
+                            egui::CollapsingHeader::new("Voice settings").id_source(("project_line_settings", line.id)).show(ui, |ui| {
+                                match &mut line.settings {
+                                    Some(settings) => {
+                                        changed |= settings.ui(ui, ("project_line_settings_grid", line.id));
+                                        if ui.button("Reset").on_hover_text("Uses the voice with its own settings").clicked() {
+                                            line.settings = None;
+                                            changed = true;
+                                        }
+                                    }
+                                    None => {
+                                        ui.small("Not tuned, the voice is used with its own settings.");
+                                        if ui.button("Tune").clicked() {
+                                            line.settings = Some(VoiceSettings::default());
+                                            changed = true;
+                                        }
+                                    }
+                                }
+                            });
+                        });
+                    }
+
+                    if ui.button("Add line").clicked() {
+                        let id = self.next_id();
+                        self.document.lines.push(ProjectLine {
+                            id,
+                            text: String::new(),
+                            voice: template.voice.clone(),
+                            model: template.model.clone(),
+                            settings: template.settings.clone(),
+                            pause: DEFAULT_PAUSE,
+                        });
+                        changed = true;
+                    }
+                });
+            });
+
+        if let Some((from, to)) = moved {
+            self.document.lines.swap(from, to);
+            changed = true;
+        }
+        if let Some(index) = delete {
+            let line = self.document.lines.remove(index);
+            self.audio.remove(&line.id);
+            self.failed.remove(&line.id);
+            changed = true;
+        }
+        if opened {
+            let path = self.path_input.clone();
+            self.open(&path)?;
+        }
+        if saved_as {
+            self.path = self.path_input.clone();
+            self.edited_at = None;
+            self.save()?;
+        }
+
+        // Typing and dragging change the project every frame, so edits are written together.
+        if changed {
+            self.edited_at.get_or_insert_with(Instant::now);
+        }
+        if let Some(edited_at) = self.edited_at {
+            let wait = SAVE_DELAY.saturating_sub(edited_at.elapsed());
+            if wait.is_zero() {
+                self.save_pending()?;
+            } else {
+                ctx.request_repaint_after(wait);
+            }
+        }
+
+        Ok(action)
+    }
+}
+
+fn read_document(path: &str) -> Result<ProjectDocument, PleaseSpeakError> {
+    let content = fs::read_to_string(PathBuf::from(path)).map_err(|e| PleaseSpeakError::Filesystem(format!("{}: {}", path, e)))?;
+    let mut document: ProjectDocument = ron::from_str(&content).map_err(|e| PleaseSpeakError::Other(format!("{}: {}", path, e)))?;
+    document.allocate_ids();
+    Ok(document)
+}
+
+/// Decodes the lines and joins them, each followed by its pause, into a single WAV file.
+fn mix(parts: Vec<(Bytes, f32)>) -> Result<Bytes, PleaseSpeakError> {
+    let parts = parts.into_iter()
+        .map(|(bytes, pause)| {
+            let mut audio = DecodedAudio::decode(&bytes)?;
+            audio.append_silence(Duration::from_secs_f32(pause.max(0.0)));
+            Ok(audio)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mixed = DecodedAudio::concat(parts, Duration::ZERO)?;
+    Ok(Bytes::from(mixed.to_wav()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(id: u64, text: &str) -> ProjectLine {
+        ProjectLine {
+            id,
+            text: text.to_string(),
+            voice: Voice::new("voice_id".to_string(), "Clyde".to_string()),
+            model: "eleven_turbo_v2_5".to_string(),
+            settings: None,
+            pause: 0.0,
+        }
+    }
+
+    fn ids(document: &ProjectDocument) -> Vec<u64> {
+        document.lines.iter().map(|line| line.id).collect()
+    }
+
+    fn wav(frames: usize, value: i16) -> Bytes {
+        let audio = DecodedAudio { channels: 1, sample_rate: 8_000, samples: vec![value; frames] };
+        Bytes::from(audio.to_wav().unwrap())
+    }
+
+    #[test]
+    fn missing_and_duplicate_ids_get_new_ones() {
+        let mut document = ProjectDocument {
+            name: String::new(),
+            lines: vec![line(0, "a"), line(3, "b"), line(0, "c"), line(3, "d"), line(1, "e")],
+        };
+        document.allocate_ids();
+        assert_eq!(ids(&document), vec![4, 3, 5, 6, 1]);
+
+        let mut document = ProjectDocument { name: String::new(), lines: vec![line(0, "a"), line(0, "b")] };
+        document.allocate_ids();
+        assert_eq!(ids(&document), vec![1, 2]);
+    }
+
+    #[test]
+    fn hand_written_files_get_distinct_ids() {
+        let path = std::env::temp_dir().join(format!("please_speak_project_{}.ron", std::process::id()));
+        let content = r#"(name: "Scene", lines: [
+            (text: "Hello", voice: (voice_id: "a", voice_name: "Clyde"), model: "m"),
+            (text: "Hi", voice: (voice_id: "b", voice_name: "Rachel"), model: "m", pause: 0.5),
+        ])"#;
+        fs::write(&path, content).unwrap();
+
+        let document = read_document(&path.display().to_string()).unwrap();
+        assert_eq!(ids(&document), vec![1, 2]);
+        assert_eq!(document.lines[1].pause, 0.5);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn mix_joins_lines_with_their_pauses() {
+        let mixed = mix(vec![(wav(800, 1000), 0.25), (wav(400, -1000), 0.0)]).unwrap();
+        let decoded = DecodedAudio::decode(&mixed).unwrap();
+
+        assert_eq!((decoded.channels, decoded.sample_rate), (1, 8_000));
+        assert_eq!(decoded.frames(), 800 + 2_000 + 400);
+        assert!(decoded.samples[..800].iter().all(|sample| *sample == 1000));
+        assert!(decoded.samples[800..2_800].iter().all(|sample| *sample == 0));
+        assert!(decoded.samples[2_800..].iter().all(|sample| *sample == -1000));
+    }
+
+    #[test]
+    fn mix_refuses_what_is_not_audio() {
+        assert!(mix(vec![(Bytes::from_static(b"not audio"), 0.0)]).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use async_channel::Sender;
+use elevenlabs_rs::Bytes;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::runtime::{Builder, Runtime};
+use crate::{Elabs, Model, Voice, VoiceSettings};
+use crate::errors::PleaseSpeakError;
+use crate::retry::RetryPolicy;
+
+/// What a speech engine is able to do, so the UI can hide what it does not support.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub streaming: bool,
+    pub model_selection: bool,
+    pub voice_settings: bool,
+}
+
+/// Where the app is with connecting to the provider.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+    /// The reason the last attempt failed.
+    Failed(PleaseSpeakError),
+}
+
+impl ConnectionState {
+    pub fn get_name(&self) -> &str {
+        match self {
+            ConnectionState::Disconnected => "Disconnected",
+            ConnectionState::Connecting => "Connecting...",
+            ConnectionState::Connected => "Connected",
+            ConnectionState::Failed(_) => "Connection failed",
+        }
+    }
+}
+
+/// Who the API key belongs to and how much of its plan is used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Account {
+    pub name: Option<String>,
+    pub tier: String,
+    pub character_count: u64,
+    pub character_limit: u64,
+    /// Unix timestamp in seconds of the next quota reset.
+    pub next_reset: Option<i64>,
+}
+
+/// Everything a generation depends on, so the same request always gives the same audio.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct SpeechRequest {
+    pub text: String,
+    pub voice: Voice,
+    pub model: String,
+    /// `None` leaves the voice as it is saved with the provider.
+    pub settings: Option<VoiceSettings>,
+    /// Text spoken right before and after this one, for continuity between segments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_text: Option<String>,
+}
+
+/// A text-to-speech engine the app can talk to.
+///
+/// Every call returns why it failed, the caller decides whether the user is told.
+pub trait SpeechProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    fn connected(&self) -> bool;
+
+    /// Encoding of the audio returned by [`Self::generate_speak`].
+    fn output_format(&self) -> &str;
+
+    fn get_voices(&self) -> BoxFuture<'_, Result<Vec<Voice>, PleaseSpeakError>>;
+
+    fn get_account(&self) -> BoxFuture<'_, Result<Account, PleaseSpeakError>>;
+
+    /// Models usable for text to speech, only called when `model_selection` is supported.
+    fn get_models(&self) -> BoxFuture<'_, Result<Vec<Model>, PleaseSpeakError>>;
+
+    /// Settings saved with the voice on the provider side, only called when `voice_settings` is supported.
+    fn get_voice_settings<'a>(&'a self, voice: &'a Voice) -> BoxFuture<'a, Result<VoiceSettings, PleaseSpeakError>>;
+
+    fn generate_speak(&self, request: SpeechRequest) -> BoxFuture<'_, Result<Bytes, PleaseSpeakError>>;
+
+    /// Same as [`Self::generate_speak`], also sending each chunk to `chunks` as it arrives.
+    /// `chunks` is closed once the audio is complete or the generation failed.
+    ///
+    /// Providers without `streaming` send the whole audio as a single chunk.
+    fn generate_stream(&self, request: SpeechRequest, chunks: Sender<Bytes>) -> BoxFuture<'_, Result<Bytes, PleaseSpeakError>> {
+        Box::pin(async move {
+            let bytes = self.generate_speak(request).await?;
+            let _ = chunks.send(bytes.clone()).await;
+            Ok(bytes)
+        })
+    }
+}
+
+/// The engine selected in the configuration.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum ProviderKind {
+    #[default]
+    ElevenLabs,
+}
+
+impl ProviderKind {
+    pub const ALL: [ProviderKind; 1] = [ProviderKind::ElevenLabs];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            ProviderKind::ElevenLabs => "ElevenLabs",
+        }
+    }
+
+    /// Builds and connects the provider, returning why the connection failed otherwise.
+    pub async fn create(&self, api_key: String, retry: RetryPolicy) -> Result<Arc<dyn SpeechProvider>, PleaseSpeakError> {
+        match self {
+            ProviderKind::ElevenLabs => {
+                let mut elabs = Elabs::new(retry);
+                elabs.init(api_key).await?;
+                Ok(Arc::new(elabs))
+            }
+        }
+    }
+}
+
+/// The runtime every request runs on, started on first use and shared by the whole process
+/// so requests run concurrently and reuse the same HTTP connections.
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("please-speak-worker")
+            .build()
+            .expect("Failed to start the async runtime")
+    })
+}
+
+/// Waits for `future` on the shared runtime, from code that does not run on it.
+pub fn run_sync<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
@@ -0,0 +1,190 @@
+use std::future::Future;
+use std::time::Duration;
+use ring::rand::{SecureRandom, SystemRandom};
+use crate::errors::PleaseSpeakError;
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// A retry about to happen: the attempt that failed, what it failed with and the wait before
+/// the next one.
+#[derive(Clone, Debug)]
+pub struct Retry {
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub error: PleaseSpeakError,
+    pub delay: Duration,
+}
+
+type Observer = Box<dyn Fn(Retry) + Send + Sync>;
+
+tokio::task_local! {
+    static OBSERVER: Observer;
+}
+
+/// Runs `future`, calling `observer` before each retry made by the requests it sends, so a job
+/// can show its attempts without the provider knowing about jobs.
+pub async fn observe<F: Future>(observer: impl Fn(Retry) + Send + Sync + 'static, future: F) -> F::Output {
+    OBSERVER.scope(Box::new(observer), future).await
+}
+
+/// How failed requests are tried again: exponential backoff with full jitter, unless the
+/// provider said how long to wait.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, `1` never retries.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ATTEMPTS)
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay: BASE_DELAY,
+            max_delay: MAX_DELAY,
+        }
+    }
+
+    /// How long to wait after `attempt` failed with `error`, counting attempts from 1.
+    ///
+    /// Never more than `max_delay`, even when the provider asks for longer.
+    pub fn delay(&self, attempt: u32, error: &PleaseSpeakError) -> Duration {
+        if let PleaseSpeakError::RateLimited { retry_after: Some(retry_after), .. } = error {
+            return (*retry_after).min(self.max_delay);
+        }
+
+        let ceiling = self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        ceiling.mul_f64(jitter())
+    }
+
+    /// Calls `request` until it succeeds, fails with an error that would fail again, or runs out
+    /// of attempts.
+    pub async fn run<T, F, Fut>(&self, mut request: F) -> Result<T, PleaseSpeakError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, PleaseSpeakError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Ok(value) => return Ok(value),
+                Err(error) if attempt < self.max_attempts && error.is_transient() => {
+                    let delay = self.delay(attempt, &error);
+                    let _ = OBSERVER.try_with(|observer| observer(Retry {
+                        attempt,
+                        max_attempts: self.max_attempts,
+                        error,
+                        delay,
+                    }));
+
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+/// A random factor up to 1, so clients that failed together do not retry together.
+fn jitter() -> f64 {
+    let mut bytes = [0u8; 4];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return 1.0;
+    }
+    u32::from_le_bytes(bytes) as f64 / (u32::MAX as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+    use super::*;
+
+    fn rate_limited(retry_after: u64) -> PleaseSpeakError {
+        PleaseSpeakError::RateLimited { message: "Slow down".to_string(), retry_after: Some(Duration::from_secs(retry_after)) }
+    }
+
+    #[test]
+    fn delay_backs_off_under_the_ceiling() {
+        let policy = RetryPolicy::new(10);
+        let error = PleaseSpeakError::Network("reset".to_string());
+        for attempt in 1..=10 {
+            let ceiling = (BASE_DELAY * 2u32.pow(attempt - 1)).min(MAX_DELAY);
+            assert!(policy.delay(attempt, &error) <= ceiling, "attempt {}", attempt);
+        }
+        assert!(policy.delay(u32::MAX, &error) <= MAX_DELAY);
+    }
+
+    #[test]
+    fn retry_after_is_clamped_to_the_max_delay() {
+        let policy = RetryPolicy::new(4);
+        assert_eq!(policy.delay(1, &rate_limited(2)), Duration::from_secs(2));
+        assert_eq!(policy.delay(1, &rate_limited(3600)), MAX_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_retries_transient_errors() {
+        let calls = AtomicU32::new(0);
+        let start = tokio::time::Instant::now();
+        let result = RetryPolicy::new(4).run(|| async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(rate_limited(5)),
+                1 => Err(PleaseSpeakError::Network("reset".to_string())),
+                call => Ok(call),
+            }
+        }).await;
+
+        assert_eq!(result, Ok(2));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_gives_up_after_max_attempts() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = RetryPolicy::new(3).run(|| async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(PleaseSpeakError::Network("reset".to_string()))
+        }).await;
+
+        assert_eq!(result, Err(PleaseSpeakError::Network("reset".to_string())));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_does_not_retry_permanent_errors() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = RetryPolicy::new(4).run(|| async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(PleaseSpeakError::Auth("Invalid API key".to_string()))
+        }).await;
+
+        assert!(matches!(result, Err(PleaseSpeakError::Auth(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn observer_sees_each_retry() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let observed = seen.clone();
+        let result: Result<(), _> = observe(
+            move |retry: Retry| observed.lock().unwrap().push((retry.attempt, retry.max_attempts, retry.delay)),
+            RetryPolicy::new(3).run(|| async { Err(rate_limited(1)) }),
+        ).await;
+
+        assert!(result.is_err());
+        assert_eq!(*seen.lock().unwrap(), vec![(1, 3, Duration::from_secs(1)), (2, 3, Duration::from_secs(1))]);
+    }
+}
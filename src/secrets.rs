@@ -0,0 +1,170 @@
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use crate::app::APP_NAME;
+use crate::errors::PleaseSpeakError;
+
+const KEYRING_USER: &str = "api_key";
+const ENCRYPTED_FILE: &str = "api_key.enc";
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+/// Where the API key is kept. It only stays in the app configuration until a key from an older version is moved here.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum KeyStorage {
+    #[default]
+    Keyring,
+    EncryptedFile,
+}
+
+impl KeyStorage {
+    pub const ALL: [KeyStorage; 2] = [KeyStorage::Keyring, KeyStorage::EncryptedFile];
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            KeyStorage::Keyring => "System keyring",
+            KeyStorage::EncryptedFile => "Encrypted file",
+        }
+    }
+
+    /// Reads the stored key, `None` when nothing was stored yet.
+    ///
+    /// `passphrase` is only used by [`KeyStorage::EncryptedFile`].
+    pub fn load(&self, passphrase: &str) -> Result<Option<String>, PleaseSpeakError> {
+        match self {
+            KeyStorage::Keyring => match keyring_entry()?.get_password() {
+                Ok(api_key) => Ok(Some(api_key)),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(e) => Err(PleaseSpeakError::Other(format!("The system keyring failed: {}", e))),
+            },
+            KeyStorage::EncryptedFile => {
+                let Ok(content) = fs::read(encrypted_file_path()) else {
+                    return Ok(None);
+                };
+                decrypt(&content, passphrase).map(Some)
+            }
+        }
+    }
+
+    pub fn save(&self, api_key: &str, passphrase: &str) -> Result<(), PleaseSpeakError> {
+        match self {
+            KeyStorage::Keyring => keyring_entry()?.set_password(api_key).map_err(|e| PleaseSpeakError::Other(format!("The system keyring failed: {}", e))),
+            KeyStorage::EncryptedFile => {
+                if passphrase.is_empty() {
+                    return Err(PleaseSpeakError::Other("A passphrase is needed to encrypt the API key".to_string()));
+                }
+
+                let path = encrypted_file_path();
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+                }
+                fs::write(path, encrypt(api_key, passphrase)?).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))
+            }
+        }
+    }
+
+    /// Removes the stored key, so switching storage does not leave a copy behind.
+    pub fn delete(&self) -> Result<(), PleaseSpeakError> {
+        match self {
+            KeyStorage::Keyring => match keyring_entry()?.delete_password() {
+                Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+                Err(e) => Err(PleaseSpeakError::Other(format!("The system keyring failed: {}", e))),
+            },
+            KeyStorage::EncryptedFile => {
+                let path = encrypted_file_path();
+                if path.exists() {
+                    fs::remove_file(path).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        match self {
+            KeyStorage::Keyring => matches!(self.load(""), Ok(Some(_))),
+            KeyStorage::EncryptedFile => encrypted_file_path().exists(),
+        }
+    }
+}
+
+fn keyring_entry() -> Result<keyring::Entry, PleaseSpeakError> {
+    keyring::Entry::new(APP_NAME, KEYRING_USER).map_err(|e| PleaseSpeakError::Other(format!("The system keyring failed: {}", e)))
+}
+
+fn encrypted_file_path() -> PathBuf {
+    eframe::storage_dir(APP_NAME).unwrap_or_else(std::env::temp_dir).join(ENCRYPTED_FILE)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<LessSafeKey, PleaseSpeakError> {
+    let mut key = [0u8; 32];
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| PleaseSpeakError::Other("Could not derive the key".to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Encrypts with AES-256-GCM under a PBKDF2 key, laid out as salt, nonce then ciphertext.
+fn encrypt(api_key: &str, passphrase: &str) -> Result<Vec<u8>, PleaseSpeakError> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).map_err(|_| PleaseSpeakError::Other("No randomness available".to_string()))?;
+    rng.fill(&mut nonce).map_err(|_| PleaseSpeakError::Other("No randomness available".to_string()))?;
+
+    let mut ciphertext = api_key.as_bytes().to_vec();
+    derive_key(passphrase, &salt)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut ciphertext)
+        .map_err(|_| PleaseSpeakError::Other("Could not encrypt the API key".to_string()))?;
+
+    Ok([&salt[..], &nonce[..], &ciphertext].concat())
+}
+
+fn decrypt(content: &[u8], passphrase: &str) -> Result<String, PleaseSpeakError> {
+    if content.len() < SALT_LEN + NONCE_LEN {
+        return Err(PleaseSpeakError::Other("The encrypted API key file is corrupted".to_string()));
+    }
+
+    let (salt, rest) = content.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| PleaseSpeakError::Other("The encrypted API key file is corrupted".to_string()))?;
+
+    let mut plaintext = ciphertext.to_vec();
+    let api_key = derive_key(passphrase, salt)?
+        .open_in_place(nonce, Aad::empty(), &mut plaintext)
+        .map_err(|_| PleaseSpeakError::Other("Wrong passphrase".to_string()))?;
+
+    String::from_utf8(api_key.to_vec()).map_err(|_| PleaseSpeakError::Other("The encrypted API key file is corrupted".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_key_round_trips() {
+        let content = encrypt("sk_0123456789", "correct horse").unwrap();
+        assert_eq!(decrypt(&content, "correct horse").unwrap(), "sk_0123456789");
+        // Salt and nonce are random, so the same key never gives the same file twice.
+        assert_ne!(encrypt("sk_0123456789", "correct horse").unwrap(), content);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let content = encrypt("sk_0123456789", "correct horse").unwrap();
+        assert_eq!(decrypt(&content, "battery staple").unwrap_err(), PleaseSpeakError::Other("Wrong passphrase".to_string()));
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let content = encrypt("sk_0123456789", "correct horse").unwrap();
+        assert_eq!(decrypt(&content[..SALT_LEN + 4], "correct horse").unwrap_err(), PleaseSpeakError::Other("The encrypted API key file is corrupted".to_string()));
+        assert!(decrypt(&content[..content.len() - 1], "correct horse").is_err());
+        assert!(decrypt(&[], "correct horse").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::time::Duration;
+use elevenlabs_rs::Bytes;
+use crate::audio::DecodedAudio;
+use crate::cache::SpeechCache;
+use crate::errors::PleaseSpeakError;
+use crate::provider::{SpeechProvider, SpeechRequest};
+
+/// Overlap between two segments, long enough to hide the seam without eating words.
+const CROSSFADE: Duration = Duration::from_millis(30);
+
+/// Splits `text` in segments of at most `max_characters`, cutting between paragraphs when
+/// possible, then between sentences, then between words.
+pub fn split_text(text: &str, max_characters: usize) -> Vec<String> {
+    if max_characters == 0 {
+        return vec![text.to_string()];
+    }
+
+    split_level(text, max_characters, 0)
+        .into_iter()
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+fn split_level(text: &str, max_characters: usize, level: usize) -> Vec<String> {
+    if text.chars().count() <= max_characters {
+        return vec![text.trim().to_string()];
+    }
+
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    for unit in units(text, level) {
+        if unit.chars().count() > max_characters {
+            segments.push(current.trim().to_string());
+            current.clear();
+            segments.extend(split_level(unit, max_characters, level + 1));
+            continue;
+        }
+
+        if current.chars().count() + unit.chars().count() > max_characters {
+            segments.push(current.trim().to_string());
+            current.clear();
+        }
+        current.push_str(unit);
+    }
+    segments.push(current.trim().to_string());
+
+    segments
+}
+
+/// Cuts `text` after each boundary of the given level, keeping the separators so the
+/// pieces join back into `text`.
+fn units(text: &str, level: usize) -> Vec<&str> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, char)) = chars.next() {
+        let next = chars.peek().map(|(_, next)| *next);
+        let boundary = match level {
+            0 => char == '\n' && next == Some('\n'),
+            1 => char == '\n' || (matches!(char, '.' | '!' | '?' | '…' | ';') && next.map_or(false, char::is_whitespace)),
+            2 => char.is_whitespace(),
+            _ => true,
+        };
+
+        if boundary {
+            // Keep the whitespace that follows with the unit it ends.
+            let mut end = index + char.len_utf8();
+            while let Some((next_index, next)) = chars.peek() {
+                if level == 3 || !next.is_whitespace() {
+                    break;
+                }
+                end = next_index + next.len_utf8();
+                chars.next();
+            }
+            units.push(&text[start..end]);
+            start = end;
+        }
+    }
+    if start < text.len() {
+        units.push(&text[start..]);
+    }
+
+    units
+}
+
+/// Generates each segment of `request` with its neighbours as context, then stitches them
+/// into a single WAV file.
+///
+/// `progress` is called with the number of segments done and the total. The flag in the
+/// result tells whether every segment came from the cache.
+pub async fn generate_segments(
+    provider: &dyn SpeechProvider,
+    cache: Option<&SpeechCache>,
+    request: &SpeechRequest,
+    segments: &[String],
+    force: bool,
+    progress: impl Fn(usize, usize),
+) -> Result<(Bytes, bool), PleaseSpeakError> {
+    let mut parts = Vec::with_capacity(segments.len());
+    let mut all_cached = true;
+
+    for (index, text) in segments.iter().enumerate() {
+        let segment_request = SpeechRequest {
+            text: text.clone(),
+            previous_text: index.checked_sub(1).map(|previous| segments[previous].clone()),
+            next_text: segments.get(index + 1).cloned(),
+            ..request.clone()
+        };
+
+        let (bytes, cached) = match cache {
+            Some(cache) => cache.generate(provider, segment_request, force, None).await?,
+            None => (provider.generate_speak(segment_request).await?, false),
+        };
+
+        all_cached &= cached;
+        parts.push(DecodedAudio::decode(&bytes)?);
+        progress(index + 1, segments.len());
+    }
+
+    let joined = DecodedAudio::concat(parts, CROSSFADE)?;
+    Ok((Bytes::from(joined.to_wav()?), all_cached))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "  First paragraph. It has two sentences!\n\nSecond one; with a clause… And an extraordinarily long word.\nNew line here?  \n\nÉté, naïve café — 日本語のテキスト。 ";
+
+    /// Checks every segment fits and that, with the whitespace between them, they give back
+    /// the trimmed text.
+    fn assert_segments(text: &str, max_characters: usize) -> Vec<String> {
+        let segments = split_text(text, max_characters);
+        let mut rest = text.trim();
+        for segment in &segments {
+            assert!(!segment.is_empty());
+            assert!(segment.chars().count() <= max_characters, "{:?} is over {}", segment, max_characters);
+            rest = rest.trim_start();
+            assert!(rest.starts_with(segment.as_str()), "{:?} does not continue at {:?}", segment, rest);
+            rest = &rest[segment.len()..];
+        }
+        assert!(rest.trim().is_empty(), "{:?} was dropped", rest);
+        segments
+    }
+
+    #[test]
+    fn units_join_back_into_the_text() {
+        for level in 0..=3 {
+            assert_eq!(units(TEXT, level).concat(), TEXT, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn segments_fit_and_keep_the_text() {
+        for max_characters in 1..=TEXT.chars().count() + 1 {
+            assert_segments(TEXT, max_characters);
+        }
+    }
+
+    #[test]
+    fn short_text_is_one_trimmed_segment() {
+        assert_eq!(split_text("  Hello there.\n", 50), vec!["Hello there."]);
+        assert_eq!(split_text("Hello", 0), vec!["Hello"]);
+        assert!(split_text("   ", 10).is_empty());
+    }
+
+    #[test]
+    fn cuts_between_paragraphs_first() {
+        let segments = assert_segments("One. Two.\n\nThree. Four.", 15);
+        assert_eq!(segments, vec!["One. Two.", "Three. Four."]);
+    }
+
+    #[test]
+    fn cuts_between_sentences_in_a_long_paragraph() {
+        let segments = assert_segments("One sentence. Another one! A third?", 20);
+        assert_eq!(segments, vec!["One sentence.", "Another one!", "A third?"]);
+    }
+
+    #[test]
+    fn cuts_between_words_in_a_long_sentence() {
+        let segments = assert_segments("a sentence without any stop", 12);
+        assert_eq!(segments, vec!["a sentence", "without any", "stop"]);
+    }
+
+    #[test]
+    fn cuts_inside_a_word_longer_than_the_limit() {
+        let segments = assert_segments("hi extraordinarily", 5);
+        assert_eq!(segments, vec!["hi", "extra", "ordin", "arily"]);
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        let segments = assert_segments("日本語 のテキ スト", 3);
+        assert_eq!(segments, vec!["日本語", "のテキ", "スト"]);
+        let segments = assert_segments("éééé", 2);
+        assert_eq!(segments, vec!["éé", "éé"]);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io::Read;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use eframe::egui;
+use elevenlabs_rs::Bytes;
+use ring::constant_time::verify_slices_are_equal;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::audio::AudioFormat;
+use crate::errors::PleaseSpeakError;
+use crate::elabs::{Voice, VoiceSettings};
+use crate::jobs::{Job, JobStatus};
+
+pub const DEFAULT_API_SERVER_PORT: u16 = 7312;
+/// Request bodies past this are refused, texts are far shorter.
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+/// Requests handled at once, the others wait for a free worker.
+const WORKERS: usize = 4;
+/// How long `POST /speak` waits for the app to queue the job, it only does between frames.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What `POST /speak` takes, only `text` is required.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SpeakBody {
+    pub text: String,
+    /// Id or name of the voice, the voice selected in the app when missing.
+    pub voice: Option<String>,
+    pub model: Option<String>,
+    /// The settings tuned for the voice in the app when missing.
+    pub settings: Option<VoiceSettings>,
+    /// Plays the audio on the configured outputs once generated.
+    #[serde(default)]
+    pub play: bool,
+    /// Skips the cache lookup.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// A `POST /speak` for the app to queue on the next frame, the other requests are answered
+/// by the server from what the app published.
+pub struct ApiCall {
+    pub body: SpeakBody,
+    pub reply: Sender<ApiReply>,
+}
+
+/// The voices and jobs as of the last frame, so reading them does not wait for the app.
+#[derive(Default)]
+struct Published {
+    voices: Vec<Value>,
+    /// Each job as `GET /jobs/<id>` answers it, with its audio once done.
+    jobs: HashMap<u64, (Value, Option<Bytes>)>,
+}
+
+pub struct ApiReply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl ApiReply {
+    pub fn json(status: u16, value: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+}
+
+/// What `GET /jobs/<id>` answers.
+pub fn job_json(job: &Job) -> Value {
+    let error = match &job.status {
+        JobStatus::Failed(error) => Some(error.to_string()),
+        _ => None,
+    };
+    json!({
+        "id": job.id,
+        "status": job.status.get_name(),
+        "error": error,
+        "progress": job.progress.map(|(done, total)| json!({ "done": done, "total": total })),
+        "voice": job.spec.request.voice.get_voice_name(),
+        "model": job.spec.request.model,
+        "cached": job.result.as_ref().map(|result| result.cached),
+        "duration": job.result.as_ref().and_then(|result| result.duration),
+    })
+}
+
+/// A new random token to give to the programs allowed to use the API.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 16];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return String::new();
+    }
+    bytes.iter().fold(String::new(), |mut token, byte| {
+        let _ = write!(token, "{:02x}", byte);
+        token
+    })
+}
+
+/// The HTTP API on localhost, letting other programs request speech from the running app.
+///
+/// Requests are handled by a few worker threads. Voices and jobs are read from what the app
+/// last published, speech requests are handed to the app as [`ApiCall`]s so they go through the
+/// same job queue and player as the window. Every request needs the token as
+/// `Authorization: Bearer <token>`.
+pub struct ApiServer {
+    server: Arc<Server>,
+    workers: Vec<JoinHandle<()>>,
+    published: Arc<Mutex<Published>>,
+    /// Jobs whose audio was sent to a client, the app can let go of it.
+    fetched_rx: Receiver<u64>,
+    port: u16,
+    token: String,
+}
+
+impl ApiServer {
+    pub fn start(port: u16, token: String, calls_tx: Sender<ApiCall>, ctx: egui::Context) -> Result<Self, PleaseSpeakError> {
+        if token.is_empty() {
+            return Err(PleaseSpeakError::Server("The local API needs a token".to_string()));
+        }
+
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let server = Server::http(address)
+            .map(Arc::new)
+            .map_err(|e| PleaseSpeakError::Server(format!("Could not listen on {}: {}", address, e)))?;
+        log::info!("Local API listening on http://{}", address);
+
+        let published = Arc::new(Mutex::new(Published::default()));
+        let (fetched_tx, fetched_rx) = channel();
+        let workers = (0..WORKERS).map(|_| {
+            let server = server.clone();
+            let token = token.clone();
+            let published = published.clone();
+            let calls_tx = calls_tx.clone();
+            let fetched_tx = fetched_tx.clone();
+            let ctx = ctx.clone();
+            std::thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let reply = handle(&mut request, &token, &published, &calls_tx, &fetched_tx, &ctx);
+                    let response = Response::from_data(reply.body)
+                        .with_status_code(reply.status)
+                        .with_header(Header::from_bytes("Content-Type", reply.content_type).unwrap());
+                    let _ = request.respond(response);
+                }
+            })
+        }).collect();
+
+        Ok(Self {
+            server,
+            workers,
+            published,
+            fetched_rx,
+            port,
+            token,
+        })
+    }
+
+    /// Shares the current voices and jobs with the request threads.
+    pub fn publish(&self, voices: &[Voice], jobs: &[Job]) {
+        let voices = voices.iter()
+            .map(|voice| json!({ "id": voice.get_voice_id(), "name": voice.get_voice_name() }))
+            .collect();
+        let jobs = jobs.iter()
+            .map(|job| (job.id, (job_json(job), job.result.as_ref().map(|result| result.bytes.clone()))))
+            .collect();
+        *self.published.lock().unwrap() = Published { voices, jobs };
+    }
+
+    /// The jobs whose audio was fetched since the last call.
+    pub fn fetched(&self) -> Vec<u64> {
+        self.fetched_rx.try_iter().collect()
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn get_token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        // Each unblock wakes a single worker.
+        for _ in &self.workers {
+            self.server.unblock();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn handle(request: &mut Request, token: &str, published: &Mutex<Published>, calls_tx: &Sender<ApiCall>, fetched_tx: &Sender<u64>, ctx: &egui::Context) -> ApiReply {
+    if !authorized(request, token) {
+        return ApiReply::error(401, "Missing or wrong token");
+    }
+
+    let path = request.url().split('?').next().unwrap_or_default().trim_matches('/').to_string();
+    let segments: Vec<&str> = path.split('/').collect();
+    let body = match (request.method(), segments.as_slice()) {
+        (Method::Get, ["voices"]) => return ApiReply::json(200, json!(published.lock().unwrap().voices)),
+        (Method::Post, ["speak"]) => {
+            let mut body = String::new();
+            if let Err(error) = request.as_reader().take(MAX_BODY_SIZE).read_to_string(&mut body) {
+                return ApiReply::error(400, &error.to_string());
+            }
+            match serde_json::from_str(&body) {
+                Ok(body) => body,
+                Err(error) => return ApiReply::error(400, &format!("Invalid body: {}", error)),
+            }
+        }
+        (Method::Get, ["jobs", id]) | (Method::Get, ["jobs", id, "audio"]) => {
+            let published = published.lock().unwrap();
+            let Some((id, (job, bytes))) = id.parse().ok().and_then(|id: u64| Some((id, published.jobs.get(&id)?))) else {
+                return ApiReply::error(404, "No such job");
+            };
+            if segments.len() == 2 {
+                return ApiReply::json(200, job.clone());
+            }
+            return match bytes {
+                Some(bytes) => {
+                    let _ = fetched_tx.send(id);
+                    ApiReply {
+                        status: 200,
+                        content_type: AudioFormat::sniff(bytes).map_or("application/octet-stream", |format| format.mime_type()),
+                        body: bytes.to_vec(),
+                    }
+                }
+                None if job["status"] == JobStatus::Done.get_name() => ApiReply::error(410, "The audio was already fetched"),
+                None => ApiReply::error(409, "The job has no audio yet"),
+            };
+        }
+        _ => return ApiReply::error(404, "No such endpoint"),
+    };
+
+    let (reply_tx, reply_rx) = channel();
+    if calls_tx.send(ApiCall { body, reply: reply_tx }).is_err() {
+        return ApiReply::error(503, "The app is closing");
+    }
+    ctx.request_repaint();
+    reply_rx.recv_timeout(REPLY_TIMEOUT).unwrap_or_else(|_| ApiReply::error(503, "The app did not answer in time"))
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    request.headers().iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .filter_map(|header| header.value.as_str().strip_prefix("Bearer "))
+        .any(|given| verify_slices_are_equal(given.trim().as_bytes(), token.as_bytes()).is_ok())
+}
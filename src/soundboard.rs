@@ -0,0 +1,512 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use eframe::egui;
+use elevenlabs_rs::Bytes;
+use serde::{Deserialize, Serialize};
+use crate::app::APP_NAME;
+use crate::errors::PleaseSpeakError;
+use crate::provider::SpeechRequest;
+use crate::{Voice, VoiceSettings};
+
+const BOARD_FILE: &str = "soundboard.ron";
+const COLUMNS: usize = 4;
+const PAD_SIZE: [f32; 2] = [130.0, 40.0];
+
+/// A phrase said at the press of a button.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct SoundboardPad {
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    /// Pads of a group are shown together, the empty group is shown as "General".
+    #[serde(default)]
+    pub group: String,
+    pub text: String,
+    pub voice: Voice,
+    pub model: String,
+    #[serde(default)]
+    pub settings: Option<VoiceSettings>,
+}
+
+impl SoundboardPad {
+    pub fn request(&self) -> SpeechRequest {
+        SpeechRequest {
+            text: self.text.clone(),
+            voice: self.voice.clone(),
+            model: self.model.clone(),
+            settings: self.settings.clone(),
+            previous_text: None,
+            next_text: None,
+        }
+    }
+}
+
+fn group_name(group: &str) -> &str {
+    if group.is_empty() { "General" } else { group }
+}
+
+/// What the user asked for from the soundboard window, handled by the app.
+pub enum SoundboardAction {
+    Play(Bytes),
+    /// Renders the pad again, bypassing the cache.
+    Render(SoundboardPad),
+}
+
+/// Saved phrases rendered ahead of time, so pressing one plays it at once.
+///
+/// The pads are kept in the app storage directory. Their audio is only kept in memory, the
+/// speech cache makes rendering them again after a restart free.
+pub struct Soundboard {
+    path: PathBuf,
+    pads: Vec<SoundboardPad>,
+    /// Audio by pad, with the request it was rendered from so edited pads are rendered again.
+    audio: HashMap<u64, (SpeechRequest, Bytes)>,
+    rendering: HashSet<u64>,
+    /// Pads whose render failed, not tried again until pressed or edited.
+    failed: HashMap<u64, (SpeechRequest, String)>,
+    /// Pads pressed before their audio was ready, played once rendered.
+    pressed: HashSet<u64>,
+    /// Group shown, all of them when `None`.
+    group: Option<String>,
+    editing: bool,
+    /// Pad being edited, applied to the board once saved.
+    draft: Option<SoundboardPad>,
+    file: String,
+}
+
+impl Soundboard {
+    pub fn load() -> Self {
+        Self::open(eframe::storage_dir(APP_NAME).unwrap_or_else(std::env::temp_dir).join(BOARD_FILE))
+    }
+
+    fn open(path: PathBuf) -> Self {
+        let mut pads: Vec<SoundboardPad> = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| ron::from_str(&content).ok())
+            .unwrap_or_default();
+        allocate_ids(&mut pads);
+
+        Self {
+            path,
+            pads,
+            audio: HashMap::new(),
+            rendering: HashSet::new(),
+            failed: HashMap::new(),
+            pressed: HashSet::new(),
+            group: None,
+            editing: false,
+            draft: None,
+            file: String::new(),
+        }
+    }
+
+    /// Pads without audio for what they say now, to render.
+    pub fn unrendered(&self) -> Vec<SoundboardPad> {
+        self.pads.iter()
+            .filter(|pad| !self.rendering.contains(&pad.id))
+            .filter(|pad| {
+                let request = pad.request();
+                self.audio.get(&pad.id).map_or(true, |(rendered, _)| *rendered != request)
+                    && self.failed.get(&pad.id).map_or(true, |(failed, _)| *failed != request)
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn rendering(&mut self, id: u64) {
+        self.rendering.insert(id);
+        self.failed.remove(&id);
+    }
+
+    /// Keeps the audio of a pad, returning it when the pad was pressed meanwhile.
+    pub fn rendered(&mut self, id: u64, request: SpeechRequest, bytes: Bytes) -> Option<Bytes> {
+        self.rendering.remove(&id);
+        self.failed.remove(&id);
+        if !self.pads.iter().any(|pad| pad.id == id) {
+            return None;
+        }
+
+        self.audio.insert(id, (request, bytes.clone()));
+        self.pressed.remove(&id).then_some(bytes)
+    }
+
+    pub fn render_failed(&mut self, id: u64, request: SpeechRequest, error: String) {
+        self.rendering.remove(&id);
+        self.pressed.remove(&id);
+        self.failed.insert(id, (request, error));
+    }
+
+    fn press(&mut self, pad: &SoundboardPad) -> Option<SoundboardAction> {
+        let request = pad.request();
+        match self.audio.get(&pad.id) {
+            Some((rendered, bytes)) if *rendered == request => Some(SoundboardAction::Play(bytes.clone())),
+            _ => {
+                // Pressing a failed pad tries it again.
+                self.failed.remove(&pad.id);
+                self.pressed.insert(pad.id);
+                None
+            }
+        }
+    }
+
+    fn groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = Vec::new();
+        for pad in &self.pads {
+            if !groups.contains(&pad.group) {
+                groups.push(pad.group.clone());
+            }
+        }
+        groups
+    }
+
+    fn next_id(&self) -> u64 {
+        self.pads.iter().map(|pad| pad.id + 1).max().unwrap_or(1)
+    }
+
+    /// Swaps a pad with its neighbour in the same group, `offset` being `-1` or `1`.
+    fn move_pad(&mut self, id: u64, offset: isize) {
+        let Some(index) = self.pads.iter().position(|pad| pad.id == id) else {
+            return;
+        };
+        let group = self.pads[index].group.clone();
+        let neighbour = if offset < 0 {
+            self.pads[..index].iter().rposition(|pad| pad.group == group)
+        } else {
+            self.pads[index + 1..].iter().position(|pad| pad.group == group).map(|position| index + 1 + position)
+        };
+        if let Some(neighbour) = neighbour {
+            self.pads.swap(index, neighbour);
+        }
+    }
+
+    fn delete(&mut self, id: u64) {
+        self.pads.retain(|pad| pad.id != id);
+        self.audio.remove(&id);
+        self.failed.remove(&id);
+        self.pressed.remove(&id);
+    }
+
+    fn save(&self) -> Result<(), PleaseSpeakError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| PleaseSpeakError::Filesystem(e.to_string()))?;
+        }
+        write_pads(&self.path, &self.pads)
+    }
+
+    /// Adds the pads of a board exported with [`Self::export`] after the current ones.
+    pub fn import(&mut self, path: &str) -> Result<usize, PleaseSpeakError> {
+        let content = fs::read_to_string(path).map_err(|e| PleaseSpeakError::Filesystem(format!("{}: {}", path, e)))?;
+        let pads: Vec<SoundboardPad> = ron::from_str(&content).map_err(|e| PleaseSpeakError::Other(format!("{}: {}", path, e)))?;
+
+        let count = pads.len();
+        let mut id = self.next_id();
+        for pad in pads {
+            self.pads.push(SoundboardPad { id, ..pad });
+            id += 1;
+        }
+        self.save()?;
+        Ok(count)
+    }
+
+    pub fn export(&self, path: &str) -> Result<(), PleaseSpeakError> {
+        write_pads(Path::new(path), &self.pads)
+    }
+
+    /// Draws the soundboard window and returns the action the user clicked.
+    ///
+    /// New pads start from `template`, what the main window would generate.
+    pub fn ui(&mut self, ctx: &egui::Context, open: &mut bool, voices: &[Voice], template: &SpeechRequest) -> Result<Option<SoundboardAction>, PleaseSpeakError> {
+        let mut action = None;
+        let mut pressed = None;
+        let mut moved = None;
+        let mut delete = None;
+        let mut changed = false;
+        let mut import = false;
+        let mut export = false;
+
+        if !self.rendering.is_empty() {
+            ctx.request_repaint_after(std::time::Duration::from_millis(100));
+        }
+
+        egui::Window::new("Soundboard")
+            .open(open)
+            .default_size([580.0, 320.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let groups = self.groups();
+                    egui::ComboBox::from_id_source("soundboard_group")
+                        .selected_text(self.group.as_deref().map_or("All groups", group_name))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut self.group, None, "All groups");
+                            for group in groups {
+                                let name = group_name(&group).to_string();
+                                ui.selectable_value(&mut self.group, Some(group), name);
+                            }
+                        });
+
+                    ui.toggle_value(&mut self.editing, "Edit");
+
+                    if ui.button("Add pad").clicked() {
+                        self.draft = Some(SoundboardPad {
+                            id: self.next_id(),
+                            name: template.text.chars().take(20).collect(),
+                            group: self.group.clone().unwrap_or_default(),
+                            text: template.text.clone(),
+                            voice: template.voice.clone(),
+                            model: template.model.clone(),
+                            settings: template.settings.clone(),
+                        });
+                    }
+                });
+                ui.separator();
+
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    let groups: Vec<String> = match &self.group {
+                        Some(group) => vec![group.clone()],
+                        None => self.groups(),
+                    };
+
+                    for group in groups {
+                        if self.group.is_none() {
+                            ui.strong(group_name(&group));
+                        }
+
+                        egui::Grid::new(("soundboard_pads", group.as_str())).show(ui, |ui| {
+                            for (index, pad) in self.pads.iter().filter(|pad| pad.group == group).enumerate() {
+                                ui.vertical(|ui| {
+                                    let button = ui.add_sized(PAD_SIZE, egui::Button::new(&pad.name))
+                                        .on_hover_text(format!("{}\n{} · {}", pad.text, pad.voice.get_voice_name(), pad.model));
+                                    if button.clicked() {
+                                        pressed = Some(pad.clone());
+                                    }
+
+                                    ui.horizontal(|ui| {
+                                        if self.rendering.contains(&pad.id) {
+                                            ui.spinner();
+                                        }
+                                        if let Some((_, error)) = self.failed.get(&pad.id) {
+                                            ui.colored_label(ui.visuals().error_fg_color, "Failed").on_hover_text(error);
+                                        }
+
+                                        if self.editing {
+                                            if ui.small_button("◀").clicked() {
+                                                moved = Some((pad.id, -1));
+                                            }
+                                            if ui.small_button("▶").clicked() {
+                                                moved = Some((pad.id, 1));
+                                            }
+                                            if ui.small_button("Edit").clicked() {
+                                                self.draft = Some(pad.clone());
+                                            }
+                                            if ui.small_button("Delete").clicked() {
+                                                delete = Some(pad.id);
+                                            }
+                                        }
+                                    });
+                                });
+
+                                if (index + 1) % COLUMNS == 0 {
+                                    ui.end_row();
+                                }
+                            }
+                        });
+                        ui.add_space(8.0);
+                    }
+
+                    if self.pads.is_empty() {
+                        ui.label("No pads yet, add one from the text in the main window.");
+                    }
+                });
+
+                if let Some(draft) = &mut self.draft {
+                    ui.separator();
+                    let mut close = false;
+
+                    egui::Grid::new("soundboard_draft").num_columns(2).show(ui, |ui| {
+                        ui.label("Name:");
+                        ui.text_edit_singleline(&mut draft.name);
+                        ui.end_row();
+
+                        ui.label("Group:");
+                        ui.text_edit_singleline(&mut draft.group);
+                        ui.end_row();
+
+                        ui.label("Text:");
+                        ui.text_edit_multiline(&mut draft.text);
+                        ui.end_row();
+
+                        ui.label("Voice:");
+                        egui::ComboBox::from_id_source("soundboard_draft_voice")
+                            .selected_text(draft.voice.get_voice_name())
+                            .show_ui(ui, |ui| {
+                                for voice in voices {
+                                    ui.selectable_value(&mut draft.voice, voice.clone(), voice.get_voice_name());
+                                }
+                            });
+                        ui.end_row();
+
+                        ui.label("Model:");
+                        ui.label(&draft.model);
+                        ui.end_row();
+                    });
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Save").clicked() {
+                            match self.pads.iter_mut().find(|pad| pad.id == draft.id) {
+                                Some(pad) => *pad = draft.clone(),
+                                None => self.pads.push(draft.clone()),
+                            }
+                            changed = true;
+                            close = true;
+                        }
+                        if ui.button("Use main window settings").on_hover_text("Takes the model and voice settings of the main window").clicked() {
+                            draft.model = template.model.clone();
+                            draft.settings = template.settings.clone();
+                        }
+                        let saved = self.pads.iter().any(|pad| pad == draft);
+                        if ui.add_enabled(saved && !self.rendering.contains(&draft.id), egui::Button::new("Render again")).clicked() {
+                            action = Some(SoundboardAction::Render(draft.clone()));
+                        }
+                        if ui.button("Cancel").clicked() {
+                            close = true;
+                        }
+                    });
+
+                    if close {
+                        self.draft = None;
+                    }
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label("Board file:");
+                    ui.text_edit_singleline(&mut self.file);
+                    import = ui.add_enabled(!self.file.is_empty(), egui::Button::new("Import")).clicked();
+                    export = ui.add_enabled(!self.file.is_empty(), egui::Button::new("Export")).clicked();
+                });
+            });
+
+        if let Some(pad) = pressed {
+            action = self.press(&pad).or(action);
+        }
+        if let Some((id, offset)) = moved {
+            self.move_pad(id, offset);
+            changed = true;
+        }
+        if let Some(id) = delete {
+            self.delete(id);
+            changed = true;
+        }
+        if changed {
+            self.save()?;
+        }
+        if import {
+            self.import(&self.file.clone())?;
+        }
+        if export {
+            self.export(&self.file)?;
+        }
+
+        Ok(action)
+    }
+}
+
+/// Gives a new id to pads without one or sharing one, as in boards written by hand.
+fn allocate_ids(pads: &mut [SoundboardPad]) {
+    let mut next = pads.iter().map(|pad| pad.id + 1).max().unwrap_or(1);
+    let mut seen = HashSet::new();
+    for pad in pads {
+        if pad.id == 0 || !seen.insert(pad.id) {
+            pad.id = next;
+            next += 1;
+        }
+    }
+}
+
+fn write_pads(path: &Path, pads: &[SoundboardPad]) -> Result<(), PleaseSpeakError> {
+    let content = ron::ser::to_string_pretty(pads, ron::ser::PrettyConfig::default()).map_err(|e| PleaseSpeakError::Other(e.to_string()))?;
+    fs::write(path, content).map_err(|e| PleaseSpeakError::Filesystem(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("please_speak_soundboard_{}_{}.ron", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn pad(id: u64, name: &str, group: &str) -> SoundboardPad {
+        SoundboardPad {
+            id,
+            name: name.to_string(),
+            group: group.to_string(),
+            text: format!("{}!", name),
+            voice: Voice::new("voice_id".to_string(), "Clyde".to_string()),
+            model: "eleven_turbo_v2_5".to_string(),
+            settings: None,
+        }
+    }
+
+    fn names(board: &Soundboard) -> Vec<&str> {
+        board.pads.iter().map(|pad| pad.name.as_str()).collect()
+    }
+
+    fn ids(board: &Soundboard) -> Vec<u64> {
+        board.pads.iter().map(|pad| pad.id).collect()
+    }
+
+    #[test]
+    fn export_then_import_gives_fresh_ids() {
+        let mut board = Soundboard::open(temp_path("export"));
+        board.pads = vec![pad(1, "Hi", ""), pad(2, "Bye", "")];
+        let exported = temp_path("exported");
+        board.export(&exported.display().to_string()).unwrap();
+
+        assert_eq!(board.import(&exported.display().to_string()).unwrap(), 2);
+        assert_eq!(names(&board), vec!["Hi", "Bye", "Hi", "Bye"]);
+        assert_eq!(ids(&board), vec![1, 2, 3, 4]);
+        // The board was saved with the imported pads.
+        assert_eq!(Soundboard::open(board.path.clone()).pads, board.pads);
+    }
+
+    #[test]
+    fn hand_written_boards_get_distinct_ids() {
+        let path = temp_path("hand_written");
+        write_pads(&path, &[pad(0, "A", ""), pad(5, "B", ""), pad(0, "C", ""), pad(5, "D", "")]).unwrap();
+
+        assert_eq!(ids(&Soundboard::open(path)), vec![6, 5, 7, 8]);
+    }
+
+    #[test]
+    fn importing_something_else_fails_without_changing_the_board() {
+        let path = temp_path("not_a_board");
+        fs::write(&path, "not a board").unwrap();
+        let mut board = Soundboard::open(temp_path("unchanged"));
+        board.pads = vec![pad(1, "Hi", "")];
+
+        assert!(board.import(&path.display().to_string()).is_err());
+        assert!(board.import("/nonexistent/board.ron").is_err());
+        assert_eq!(names(&board), vec!["Hi"]);
+    }
+
+    #[test]
+    fn pads_move_within_their_group() {
+        let mut board = Soundboard::open(temp_path("move"));
+        board.pads = vec![pad(1, "A", "x"), pad(2, "B", "y"), pad(3, "C", "x"), pad(4, "D", "x")];
+
+        board.move_pad(3, -1);
+        assert_eq!(names(&board), vec!["C", "B", "A", "D"]);
+        board.move_pad(3, -1);
+        assert_eq!(names(&board), vec!["C", "B", "A", "D"]);
+        board.move_pad(1, 1);
+        assert_eq!(names(&board), vec!["C", "B", "D", "A"]);
+        board.move_pad(2, 1);
+        assert_eq!(names(&board), vec!["C", "B", "D", "A"]);
+        board.move_pad(42, 1);
+        assert_eq!(ids(&board), vec![3, 2, 4, 1]);
+    }
+}